@@ -0,0 +1,229 @@
+// Access log: one line for each response, in Common Log Format, Combined Log Format or JSON.
+// It is written to the file set in config.toml (rotated like the other log files), separately from
+// the messages printed by printing.rs.
+
+use chrono::{DateTime, Local};
+
+use std::io::{self, prelude::*};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::{AccessLogFormat, CONFIG};
+use crate::log_file::LogFile;
+
+lazy_static! {
+	// None if the access log is disabled or the file cannot be opened
+	static ref ACCESS_LOG: Option<AccessLog> = open_log();
+}
+
+// Opened on startup with the settings of [access_log], changes to them need a restart
+struct AccessLog {
+	format: AccessLogFormat,
+	output: Mutex<Output>,
+}
+
+enum Output {
+	Stdout,
+	File(LogFile),
+}
+
+// A request and the response sent to it
+pub struct Entry {
+	pub client: IpAddr,
+	// When the request was received
+	pub time: DateTime<Local>,
+	// None if the request could not be parsed
+	pub request_line: Option<String>,
+	pub status: u16,
+	// Size of the response body
+	pub bytes: u64,
+	pub referrer: Option<Vec<u8>>,
+	pub user_agent: Option<Vec<u8>>,
+	// Time taken to handle the request and send the response
+	pub duration: Duration,
+}
+
+fn open_log() -> Option<AccessLog> {
+	let config = CONFIG.get();
+	let path = config.access_log.path.as_ref()?;
+
+	// "-" is the standard output
+	let output = if path == "-" {
+		Output::Stdout
+	} else {
+		match LogFile::open(path, &config.log) {
+			Ok(f) => Output::File(f),
+			Err(e) => {
+				error!("Cannot open the access log {}: {}, access logging is disabled.", path, e);
+				return None;
+			}
+		}
+	};
+
+	Some(AccessLog {
+		format: config.access_log.format,
+		output: Mutex::new(output)
+	})
+}
+
+// Open the access log, so that errors are reported on startup instead of on the first request
+pub fn init() {
+	lazy_static::initialize(&ACCESS_LOG);
+}
+
+// Reopen the access log after it has been moved (e.g. by logrotate)
+pub fn reopen() {
+	if let Some(log) = ACCESS_LOG.as_ref() {
+		if let Output::File(f) = &mut *log.output.lock().unwrap_or_else(|e| e.into_inner()) {
+			if let Err(e) = f.reopen() {
+				error!("Cannot reopen the access log: {}", e);
+			}
+		}
+	}
+}
+
+// Append an entry to the access log, if enabled
+pub fn write(entry: &Entry) {
+	let log = match ACCESS_LOG.as_ref() {
+		Some(l) => l,
+		None => return
+	};
+
+	let line = match log.format {
+		AccessLogFormat::Common => format_common(entry),
+		AccessLogFormat::Combined => format!(
+			"{} \"{}\" \"{}\"",
+			format_common(entry),
+			entry.referrer.as_deref().map(escape_clf).unwrap_or_else(|| "-".into()),
+			entry.user_agent.as_deref().map(escape_clf).unwrap_or_else(|| "-".into())
+		),
+		AccessLogFormat::Json => format_json(entry),
+	};
+
+	let result = match &mut *log.output.lock().unwrap_or_else(|e| e.into_inner()) {
+		Output::Stdout => writeln!(io::stdout(), "{}", line),
+		Output::File(f) => f.write_line(&line),
+	};
+
+	if let Err(e) = result {
+		error!("Cannot write the access log: {}", e);
+	}
+}
+
+// host ident authuser [date] "request line" status bytes
+fn format_common(entry: &Entry) -> String {
+	format!(
+		"{} - - [{}] \"{}\" {} {}",
+		entry.client,
+		entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+		entry.request_line.as_ref().map(|r| escape_clf(r.as_bytes())).unwrap_or_else(|| "-".into()),
+		entry.status,
+		if entry.bytes > 0 { entry.bytes.to_string() } else { "-".into() }
+	)
+}
+
+fn format_json(entry: &Entry) -> String {
+	let optional = |value: Option<&[u8]>| {
+		value.map(|v| escape_json(&String::from_utf8_lossy(v))).unwrap_or_else(|| "null".into())
+	};
+
+	format!(
+		"{{\"client\":\"{}\",\"time\":\"{}\",\"request\":{},\"status\":{},\"bytes\":{},\"referrer\":{},\"user_agent\":{},\"duration_us\":{}}}",
+		entry.client,
+		entry.time.to_rfc3339(),
+		optional(entry.request_line.as_ref().map(|r| r.as_bytes())),
+		entry.status,
+		entry.bytes,
+		optional(entry.referrer.as_deref()),
+		optional(entry.user_agent.as_deref()),
+		entry.duration.as_micros()
+	)
+}
+
+// Quotes and backslashes are escaped with a backslash, control characters and non-ASCII bytes as
+// \xHH, like Apache does
+fn escape_clf(value: &[u8]) -> String {
+	let mut escaped = String::with_capacity(value.len());
+
+	for b in value {
+		match b {
+			b'"' | b'\\' => escaped.push_str(&format!("\\{}", *b as char)),
+			0x20..=0x7e => escaped.push(*b as char),
+			_ => escaped.push_str(&format!("\\x{:02x}", b)),
+		}
+	}
+
+	escaped
+}
+
+// JSON string, quotes included
+fn escape_json(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len() + 2);
+	escaped.push('"');
+
+	for c in value.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			c if (c as u32) < 0x20 || c == '\u{7f}' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+
+	escaped.push('"');
+	escaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(request_line: &str, user_agent: &[u8]) -> Entry {
+		Entry {
+			client: "127.0.0.1".parse().unwrap(),
+			time: Local::now(),
+			request_line: Some(request_line.into()),
+			status: 200,
+			bytes: 0,
+			referrer: None,
+			user_agent: Some(user_agent.to_vec()),
+			duration: Duration::from_micros(1500),
+		}
+	}
+
+	#[test]
+	fn clf_escaping() {
+		assert_eq!(escape_clf(b"Mozilla/5.0 (X11)"), "Mozilla/5.0 (X11)");
+		assert_eq!(escape_clf(br#"say "hi" \o/"#), r#"say \"hi\" \\o/"#);
+		assert_eq!(escape_clf(b"a\nb\r\tc\x00\x1b\x7f"), r"a\x0ab\x0d\x09c\x00\x1b\x7f");
+		// Each byte of the UTF-8 sequence
+		assert_eq!(escape_clf("é".as_bytes()), r"\xc3\xa9");
+	}
+
+	#[test]
+	fn json_escaping() {
+		assert_eq!(escape_json("Mozilla/5.0"), r#""Mozilla/5.0""#);
+		assert_eq!(escape_json(r#"say "hi" \o/"#), r#""say \"hi\" \\o/""#);
+		assert_eq!(escape_json("a\nb\r\tc\u{0}\u{1b}\u{7f}"), r#""a\nb\r\tc\u0000\u001b\u007f""#);
+		// JSON strings can contain any other character
+		assert_eq!(escape_json("é ✓"), "\"é ✓\"");
+	}
+
+	#[test]
+	fn lines() {
+		let e = entry("GET /caf\u{e9}\"\n HTTP/1.1", b"agent \"x\"\xff");
+
+		let common = format_common(&e);
+		assert!(common.starts_with("127.0.0.1 - - ["), "{}", common);
+		assert!(common.ends_with(r#"] "GET /caf\xc3\xa9\"\x0a HTTP/1.1" 200 -"#), "{}", common);
+
+		// Invalid UTF-8 is replaced, so that the line is valid JSON
+		let json = format_json(&e);
+		assert!(json.contains(r#""request":"GET /café\"\n HTTP/1.1","status":200,"bytes":0,"referrer":null"#), "{}", json);
+		assert!(json.ends_with(r#""user_agent":"agent \"x\"�","duration_us":1500}"#), "{}", json);
+	}
+}
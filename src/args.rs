@@ -1,7 +1,84 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use std::env;
+use std::str::FromStr;
 
 use crate::config;
 
+// Validator of the options whose value is a number
+fn is_number<T: FromStr>(value: String) -> Result<(), String> {
+	value.parse::<T>()
+		.map(|_| ())
+		.map_err(|_| format!("\"{}\" is not a valid number", value))
+}
+
+fn www_path_arg<'a, 'b>() -> Arg<'a, 'b> {
+	Arg::with_name("www-path")
+		.long("www-path")
+		.help("Replaces www_path in config.toml.")
+		.multiple(false)
+		.takes_value(true)
+		.value_name("DIR")
+}
+
+fn title_arg<'a, 'b>() -> Arg<'a, 'b> {
+	Arg::with_name("title")
+		.long("title")
+		.help("Replaces title in config.toml.")
+		.multiple(false)
+		.takes_value(true)
+		.value_name("TITLE")
+}
+
+// Options of the subcommands that use the [server] section (serve and check-config)
+fn server_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+	vec![
+		Arg::with_name("no-tls")
+			.long("no-tls")
+			.help("No SSL/TLS in HTTP requests.")
+			.multiple(false)
+			.takes_value(false),
+		Arg::with_name("address")
+			.long("address")
+			.help("Replaces address in config.toml.")
+			.multiple(false)
+			.takes_value(true)
+			.value_name("ADDRESS"),
+		Arg::with_name("port")
+			.short("p")
+			.long("port")
+			.help("Replaces the port of the address.")
+			.multiple(false)
+			.takes_value(true)
+			.value_name("PORT")
+			.validator(is_number::<u16>),
+		Arg::with_name("threads")
+			.long("threads")
+			.help("Replaces threads in config.toml.")
+			.multiple(false)
+			.takes_value(true)
+			.value_name("N")
+			.validator(|v| match v.parse::<usize>() {
+				Ok(n) if n > 0 => Ok(()),
+				_ => Err("it must be a number greater than 0".into())
+			}),
+		www_path_arg(),
+		title_arg(),
+		Arg::with_name("cert")
+			.long("cert")
+			.help("Replaces cert_path in config.toml.")
+			.multiple(false)
+			.takes_value(true)
+			.value_name("FILE"),
+		Arg::with_name("key")
+			.long("key")
+			.help("Replaces key_path in config.toml.")
+			.multiple(false)
+			.takes_value(true)
+			.value_name("FILE"),
+	]
+}
+
 pub fn parse_args() -> config::ParsedArgs {
 
 	let matches: ArgMatches = {
@@ -9,6 +86,8 @@ pub fn parse_args() -> config::ParsedArgs {
 			.version(env!("CARGO_PKG_VERSION"))
 			.author(env!("CARGO_PKG_AUTHORS"))
 			.about(env!("CARGO_PKG_DESCRIPTION"))
+			.after_help("Without a subcommand, the server is started (see serve).")
+			.setting(AppSettings::VersionlessSubcommands)
 			.arg(Arg::with_name("verbosity")
 				.short("v")
 				.long("verbosity")
@@ -20,11 +99,15 @@ Possible values:
  1 = Only errors
  2 = Errors and warnings
  3 = Errors, warnings and info
+ 4 = Errors, warnings, info and debug messages
+ 5 = Everything, trace messages included
+The [log.filters] section of config.toml can set
+a different level for some modules.
 The -s flag gets priority over this option.", config::DEFAULT_VERB))
 				.multiple(false)
 				.takes_value(true)
-				//.default_value("2")
 				.value_name("N")
+				.global(true)
 				.conflicts_with("silent"))
 			.arg(Arg::with_name("silent")
 				.short("s")
@@ -34,62 +117,121 @@ The -s flag gets priority over this option.", config::DEFAULT_VERB))
 This flag gets priority over the -v option.")
 				.conflicts_with("verbosity")
 				.multiple(false)
+				.global(true)
 				.takes_value(false))
-			.arg(Arg::with_name("no-tls")
-				.long("no-tls")
-				.help("No SSL/TLS in HTTP requests.")
+			.arg(Arg::with_name("config")
+				.short("c")
+				.long("config")
+				.long_help(&format!(
+"Takes the configuration file from the specified path.
+If not set, the {}CONFIG environment variable is used,
+then {}.", config::ENV_PREFIX, config::DEFAULT_CONFIG_PATH))
+				.multiple(false)
+				.takes_value(true)
+				.global(true)
+				.value_name("PATH"))
+			.arg(Arg::with_name("print-config")
+				.long("print-config")
+				.long_help(
+"Prints the configuration resulting from config.toml,
+the environment variables and the command line, then exits.")
 				.multiple(false)
+				.global(true)
 				.takes_value(false))
-			//.arg(Arg::with_name("config")
-			//	.short("c")
-			//	.long("config")
-			//	.help("Takes the configuration file from the specified path.")
-			//	.multiple(false)
-			//	.takes_value(true)
-			//	.value_name("PATH"))
+			.args(&server_args())
+			.subcommand(SubCommand::with_name("serve")
+				.about("Starts the server.")
+				.args(&server_args()))
+			.subcommand(SubCommand::with_name("check-config")
+				.about("Checks the configuration, prints the problems found and exits (with 1 if there are any).")
+				.args(&server_args()))
+			.subcommand(SubCommand::with_name("build")
+				.about("Renders the whole www directory into static HTML/CSS files and exits.")
+				.arg(Arg::with_name("out")
+					.short("o")
+					.long("out")
+					.help("Directory where the rendered files are written.")
+					.required(true)
+					.takes_value(true)
+					.value_name("DIR"))
+				.arg(www_path_arg())
+				.arg(title_arg()))
+			.subcommand(SubCommand::with_name("init")
+				.about("Creates config.toml and the www directory with the default content, existing files are not modified.")
+				.arg(www_path_arg()))
 			.get_matches()
 	};
 
+	// Subcommand and its options, options given without a subcommand are the ones of serve
+	let (command, sub_matches) = match matches.subcommand() {
+		("build", Some(m)) => (config::Command::Build { out_path: m.value_of("out").unwrap().into() }, m),
+		("check-config", Some(m)) => (config::Command::CheckConfig, m),
+		("init", Some(m)) => (config::Command::Init, m),
+		("serve", Some(m)) => (config::Command::Serve, m),
+		_ => (config::Command::Serve, &matches),
+	};
+
+	// Options can also be given before the subcommand
+	let value_of = |name: &str| sub_matches.value_of(name).or_else(|| matches.value_of(name));
+	let is_present = |name: &str| sub_matches.is_present(name) || matches.is_present(name);
 
 	// Parse CLI args
 	let mut args_config = config::ParsedArgs {
 		use_tls: config::DEFAULT_USE_TLS,
-		verbosity: config::DEFAULT_VERB
+		verbosity: config::DEFAULT_VERB,
+		command,
+		config_path: config::DEFAULT_CONFIG_PATH.into(),
+		address: value_of("address").map(String::from),
+		port: value_of("port").and_then(|p| p.parse().ok()),
+		threads: value_of("threads").and_then(|t| t.parse().ok()),
+		www_path: value_of("www-path").map(String::from),
+		title: value_of("title").map(String::from),
+		cert_path: value_of("cert").map(String::from),
+		key_path: value_of("key").map(String::from)
 	};
 
+	// Config file: command line, then environment variable
+	if let Some(path) = value_of("config") {
+		args_config.config_path = path.into();
+	} else if let Ok(path) = env::var(format!("{}CONFIG", config::ENV_PREFIX)) {
+		args_config.config_path = path;
+	}
+
+	if is_present("print-config") {
+		args_config.command = config::Command::PrintConfig;
+	}
+
 	// HTTP mode (no TLS)
-	if matches.is_present("no-https") {
+	if is_present("no-tls") {
 		args_config.use_tls = false;
 	}
-	
+
 	// Silent mode
 	let mut is_silent = false;
-	if matches.is_present("silent") {
+	if is_present("silent") {
 		args_config.verbosity = 0;
 		is_silent = true;
 	}
 
 	// Verbosity
 	if !is_silent {
-		let verb_val = matches.value_of("verbosity");
+		let verb_val = value_of("verbosity");
 		args_config.verbosity = config::DEFAULT_VERB;
 
-		if verb_val != None {
-			println!();
-
-			match verb_val.unwrap() {
-				"1" | "2" | "3" => {
+		if let Some(v) = verb_val {
+			match v {
+				"1" | "2" | "3" | "4" | "5" => {
 
 					// Set verbosity
-					let value = verb_val.unwrap().parse::<u8>().unwrap();
+					let value = v.parse::<u8>().unwrap();
 					args_config.verbosity = value;
 				},
 				_ => {
-					println!("Invalid verbosity level, using default value: {}.", config::DEFAULT_VERB);
+					eprintln!("Invalid verbosity level, using default value: {}.", config::DEFAULT_VERB);
 				}
 			}
 		}
 	}
 
 	args_config
-}
\ No newline at end of file
+}
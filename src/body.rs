@@ -0,0 +1,82 @@
+// Response bodies: content already in memory, parts of files (sent without loading them in memory)
+// and streams generated while sending.
+
+use std::fs::File;
+use std::io::{self, prelude::*, Cursor, SeekFrom};
+
+pub enum Body {
+	Bytes(Vec<u8>),
+	// len bytes of the file, starting from offset
+	File { file: File, offset: u64, len: u64 },
+	// The length may be unknown, in which case the body is sent with chunked transfer coding
+	Stream { reader: Box<dyn Read + Send>, len: Option<u64> },
+}
+
+impl Body {
+	pub fn empty() -> Body {
+		Body::Bytes(vec![])
+	}
+
+	// Length in bytes, None if it's unknown until the whole body is read
+	pub fn len(&self) -> Option<u64> {
+		match self {
+			Body::Bytes(b) => Some(b.len() as u64),
+			Body::File { len, .. } => Some(*len),
+			Body::Stream { len, .. } => *len,
+		}
+	}
+
+	// Part of the body, from first to last (inclusive).
+	// Returns None for streams, which cannot be split.
+	pub fn section(&self, first: u64, last: u64) -> Option<Body> {
+		match self {
+			Body::Bytes(b) => Some(Body::Bytes(b[first as usize..=last as usize].to_vec())),
+			Body::File { file, offset, .. } => Some(Body::File {
+				file: file.try_clone().ok()?,
+				offset: offset + first,
+				len: last - first + 1
+			}),
+			Body::Stream { .. } => None,
+		}
+	}
+
+	pub fn into_reader(self) -> Box<dyn Read + Send> {
+		match self {
+			Body::Bytes(b) => Box::new(Cursor::new(b)),
+			Body::File { file, offset, len } => Box::new(FileSection { file, pos: offset, end: offset + len }),
+			Body::Stream { reader, .. } => reader,
+		}
+	}
+}
+
+// Reader of a part of a file.
+// The position is set on each read, so that file handles cloned through File::try_clone (which share
+// the cursor) can be read one after another.
+struct FileSection {
+	file: File,
+	pos: u64,
+	end: u64,
+}
+
+impl Read for FileSection {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let remaining = self.end.saturating_sub(self.pos);
+
+		if remaining == 0 || buf.is_empty() {
+			return Ok(0);
+		}
+
+		let max_len = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+
+		self.file.seek(SeekFrom::Start(self.pos))?;
+		let read_bytes = self.file.read(&mut buf[..max_len])?;
+
+		// The file got truncated while reading it
+		if read_bytes == 0 {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shorter than expected"));
+		}
+
+		self.pos += read_bytes as u64;
+		Ok(read_bytes)
+	}
+}
@@ -0,0 +1,164 @@
+// Ahead-of-time rendering of the whole WWW directory into static files.
+// Markdown pages become HTML pages, SCSS stylesheets become CSS stylesheets and the other files
+// are copied as they are.
+
+use regex::{Captures, Regex};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::CONFIG;
+use crate::css::{get_default_grass_options, sass_to_css};
+use crate::html::md_to_html;
+use crate::io_ops::get_file_content;
+
+lazy_static! {
+	// Links and resources in the rendered HTML
+	static ref LINK_REGEX: Regex = Regex::new(r#"(href|src)="([^"]*)""#).unwrap();
+	// Links with a scheme (https:, mailto:, etc...)
+	static ref SCHEME_REGEX: Regex = Regex::new(r"^[a-zA-Z][a-zA-Z0-9+.-]*:").unwrap();
+}
+
+// Rewrite a link so that it points to the built file: extensionless paths get the .html extension,
+// .md and .scss files become .html and .css files
+fn rewrite_link(link: &str) -> String {
+	// External links and anchors are left as they are
+	if link.is_empty() || link.starts_with('#') || link.starts_with("//") || SCHEME_REGEX.is_match(link) {
+		return link.to_owned();
+	}
+
+	// Query and fragment are kept as they are
+	let path_end = link.find(['?', '#']).unwrap_or(link.len());
+	let (path, rest) = link.split_at(path_end);
+	let file_name = path.rsplit('/').next().unwrap_or("");
+
+	let new_path = {
+		if let Some(p) = path.strip_suffix(".md") {
+			format!("{}.html", p)
+		} else if let Some(p) = path.strip_suffix(".scss") {
+			format!("{}.css", p)
+		} else if !file_name.is_empty() && !file_name.contains('.') {
+			format!("{}.html", path)
+		} else {
+			path.to_owned()
+		}
+	};
+
+	format!("{}{}", new_path, rest)
+}
+
+fn rewrite_links(html: &str) -> String {
+	LINK_REGEX.replace_all(html, |caps: &Captures| {
+		format!(r#"{}="{}""#, &caps[1], rewrite_link(&caps[2]))
+	}).to_string()
+}
+
+// Render or copy a single file.
+// rel_path is relative to WWW, out_dir is the output directory.
+fn build_file(rel_path: &str, out_dir: &Path) -> Result<(), String> {
+	let mut out_path: PathBuf = out_dir.join(rel_path);
+
+	let content: Vec<u8> = {
+		if rel_path.ends_with(".md") {
+			out_path.set_extension("html");
+
+			let html = md_to_html(rel_path)
+				.map_err(|e| format!("cannot render the file: {}", e))?;
+
+			rewrite_links(&html).into_bytes()
+		} else if rel_path.ends_with(".scss") {
+			out_path.set_extension("css");
+
+			let content = get_file_content(rel_path)
+				.map_err(|e| format!("cannot read the file: {}", e))?;
+			let sass = String::from_utf8(content)
+				.map_err(|_| "the file is not valid UTF-8".to_owned())?;
+
+			sass_to_css(sass, get_default_grass_options())
+				.map_err(|_| "cannot compile the file into CSS".to_owned())?
+				.into_bytes()
+		} else {
+			get_file_content(rel_path)
+				.map_err(|e| format!("cannot read the file: {}", e))?
+		}
+	};
+
+	if let Some(parent) = out_path.parent() {
+		fs::create_dir_all(parent)
+			.map_err(|e| format!("cannot create the directory {}: {}", parent.display(), e))?;
+	}
+
+	fs::write(&out_path, content)
+		.map_err(|e| format!("cannot write {}: {}", out_path.display(), e))
+}
+
+// Collect the paths (relative to WWW) of all the files in a WWW subdirectory
+fn collect_files(rel_dir: &Path, files: &mut Vec<String>, errors: &mut Vec<(String, String)>) {
+	let dir = Path::new(&CONFIG.get().server.www_path).join(rel_dir);
+
+	let entries = match fs::read_dir(&dir) {
+		Ok(e) => e,
+		Err(e) => {
+			errors.push((rel_dir.display().to_string(), format!("cannot read the directory: {}", e)));
+			return;
+		}
+	};
+
+	for entry in entries.flatten() {
+		let rel_path = rel_dir.join(entry.file_name());
+
+		// Hidden files cannot be sent, they are not part of the website
+		if !CONFIG.get().server.hidden_files && entry.file_name().to_string_lossy().starts_with('.') {
+			continue;
+		}
+
+		match entry.file_type() {
+			Ok(t) if t.is_dir() => collect_files(&rel_path, files, errors),
+			Ok(_) => {
+				// Sass partials are only meant to be imported
+				let is_partial = rel_path.extension().is_some_and(|e| e == "scss")
+					&& entry.file_name().to_string_lossy().starts_with('_');
+
+				if !is_partial {
+					files.push(rel_path.to_string_lossy().into_owned());
+				}
+			},
+			Err(e) => errors.push((rel_path.display().to_string(), format!("cannot get the file type: {}", e)))
+		}
+	}
+}
+
+// Build the whole WWW directory into out_dir.
+// Returns Err if at least one file could not be built, errors are reported for each file.
+pub fn build_site(out_dir: &str) -> Result<(), ()> {
+	let out_dir = Path::new(out_dir);
+	let mut files: Vec<String> = vec![];
+	let mut errors: Vec<(String, String)> = vec![];
+
+	collect_files(Path::new(""), &mut files, &mut errors);
+	files.sort();
+
+	let mut built_files: usize = 0;
+
+	for rel_path in files {
+		match build_file(&rel_path, out_dir) {
+			Ok(_) => {
+				info!("Built {}", rel_path);
+				built_files += 1;
+			},
+			Err(e) => errors.push((rel_path, e))
+		}
+	}
+
+	for (path, error) in &errors {
+		error!("{}: {}", path, error);
+	}
+
+	info!("{} files built into {}, {} errors.", built_files, out_dir.display(), errors.len());
+
+	if errors.is_empty() {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
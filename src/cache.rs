@@ -0,0 +1,239 @@
+// In-memory cache of the content rendered from the files in WWW (HTML from Markdown, CSS from SCSS).
+// The least recently used entries are evicted when the cache exceeds its maximum size.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use crate::config::CONFIG;
+use crate::io_ops::get_file_metadata;
+
+lazy_static! {
+	static ref RENDER_CACHE: Mutex<Cache> = Mutex::new(Cache::new(CONFIG.get().cache.max_size));
+}
+
+struct Entry {
+	content: String,
+	// Modification time and size of the source file when the content was rendered
+	source_modified: SystemTime,
+	source_len: u64,
+	// Value of Cache::clock when the entry was last used
+	last_used: u64,
+}
+
+struct Cache {
+	entries: HashMap<String, Entry>,
+	// Paths of the entries by last use, the first one is the least recently used
+	usage: BTreeMap<u64, String>,
+	// Size in bytes of the cached content
+	size: usize,
+	max_size: usize,
+	// Incremented on each access, it orders the entries by last use
+	clock: u64,
+	hits: u64,
+	misses: u64,
+}
+
+impl Cache {
+	fn new(max_size: usize) -> Cache {
+		Cache {
+			entries: HashMap::new(),
+			usage: BTreeMap::new(),
+			size: 0,
+			max_size,
+			clock: 0,
+			hits: 0,
+			misses: 0
+		}
+	}
+
+	// Get the content if the source file didn't change since it was rendered
+	fn get(&mut self, path: &str, modified: SystemTime, len: u64) -> Option<String> {
+		self.clock += 1;
+
+		let is_valid = match self.entries.get_mut(path) {
+			Some(e) if e.source_modified == modified && e.source_len == len => {
+				let path = self.usage.remove(&e.last_used).unwrap();
+				self.usage.insert(self.clock, path);
+				e.last_used = self.clock;
+				true
+			},
+			Some(_) => false,
+			None => {
+				self.misses += 1;
+				return None;
+			}
+		};
+
+		if is_valid {
+			self.hits += 1;
+			return Some(self.entries[path].content.clone());
+		}
+
+		// Stale entry
+		self.misses += 1;
+		self.remove(path);
+		None
+	}
+
+	fn insert(&mut self, path: &str, content: String, modified: SystemTime, len: u64) {
+		let entry_size = path.len() + content.len();
+
+		// Too big to be cached at all
+		if entry_size > self.max_size {
+			return;
+		}
+
+		self.remove(path);
+
+		// Evict the least recently used entries
+		while self.size + entry_size > self.max_size {
+			let lru_path = self.usage.values().next().unwrap().clone();
+			self.remove(&lru_path);
+		}
+
+		self.clock += 1;
+		self.size += entry_size;
+		self.usage.insert(self.clock, path.to_owned());
+		self.entries.insert(path.to_owned(), Entry {
+			content,
+			source_modified: modified,
+			source_len: len,
+			last_used: self.clock
+		});
+	}
+
+	fn remove(&mut self, path: &str) {
+		if let Some(e) = self.entries.remove(path) {
+			self.usage.remove(&e.last_used);
+			self.size -= path.len() + e.content.len();
+		}
+	}
+}
+
+// Lock the cache. A panic while holding the lock leaves the cache consistent (entries are inserted
+// and removed as a whole), so the poisoning is ignored.
+fn lock_cache() -> MutexGuard<'static, Cache> {
+	RENDER_CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Remove all the entries, the maximum size is read again from the configuration
+pub fn clear() {
+	*lock_cache() = Cache::new(CONFIG.get().cache.max_size);
+}
+
+// Return the content rendered from the file at path (relative to WWW), render() is called only if
+// the content is not cached or the file changed since it was cached
+pub fn get_or_render<E, F: FnOnce() -> Result<String, E>>(path: &str, render: F) -> Result<String, E> {
+
+	// Without metadata the source cannot be validated, don't use the cache
+	let (modified, len) = match get_file_metadata(path).ok().and_then(|m| Some((m.modified().ok()?, m.len()))) {
+		Some(v) if CONFIG.get().cache.max_size > 0 => v,
+		_ => return render()
+	};
+
+	{
+		let mut cache = lock_cache();
+
+		if let Some(content) = cache.get(path, modified, len) {
+			debug!("Cache hit for {} (hits: {}, misses: {})", path, cache.hits, cache.misses);
+			return Ok(content);
+		}
+
+		debug!("Cache miss for {} (hits: {}, misses: {})", path, cache.hits, cache.misses);
+	}
+
+	// The lock is not held while rendering
+	let content = render()?;
+
+	lock_cache().insert(path, content.clone(), modified, len);
+
+	Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::fs;
+	use std::time::Duration;
+
+	use crate::config::test_www_path;
+
+	fn time(secs: u64) -> SystemTime {
+		SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+	}
+
+	// Each entry takes 10 bytes (path and content)
+	fn insert(cache: &mut Cache, path: &str) {
+		cache.insert(path, "x".repeat(10 - path.len()), time(1), 1);
+	}
+
+	#[test]
+	fn lru_eviction() {
+		let mut cache = Cache::new(30);
+		insert(&mut cache, "a");
+		insert(&mut cache, "b");
+		insert(&mut cache, "c");
+
+		// "a" becomes the most recently used, "b" is evicted first
+		assert!(cache.get("a", time(1), 1).is_some());
+		insert(&mut cache, "d");
+		assert!(cache.get("b", time(1), 1).is_none());
+		insert(&mut cache, "e");
+		assert!(cache.get("c", time(1), 1).is_none());
+
+		for path in ["a", "d", "e"] {
+			assert!(cache.get(path, time(1), 1).is_some(), "{} has been evicted", path);
+		}
+		assert_eq!(cache.size, 30);
+		assert_eq!(cache.usage.len(), 3);
+
+		// Replacing an entry doesn't evict the others
+		insert(&mut cache, "a");
+		assert_eq!(cache.entries.len(), 3);
+
+		// Too big to be cached
+		cache.insert("f", "x".repeat(30), time(1), 1);
+		assert!(cache.get("f", time(1), 1).is_none());
+		assert_eq!(cache.entries.len(), 3);
+	}
+
+	#[test]
+	fn stale_entries() {
+		let mut cache = Cache::new(100);
+		insert(&mut cache, "a");
+
+		assert!(cache.get("a", time(2), 1).is_none());
+		assert!(cache.entries.is_empty());
+
+		insert(&mut cache, "a");
+		assert!(cache.get("a", time(1), 2).is_none());
+		assert_eq!(cache.size, 0);
+		assert!(cache.usage.is_empty());
+	}
+
+	#[test]
+	fn render_on_change() {
+		let dir = test_www_path().join("cache");
+		fs::create_dir_all(&dir).unwrap();
+		let path = "cache/page.md";
+		let render = |content: &str| get_or_render(path, || Ok::<_, ()>(content.to_owned())).unwrap();
+
+		fs::write(dir.join("page.md"), "1").unwrap();
+		assert_eq!(render("first"), "first");
+		assert_eq!(render("not rendered"), "first");
+
+		// Same modification time, another size
+		let modified = fs::metadata(dir.join("page.md")).unwrap().modified().unwrap();
+		fs::write(dir.join("page.md"), "22").unwrap();
+		fs::File::options().write(true).open(dir.join("page.md")).unwrap().set_modified(modified).unwrap();
+		assert_eq!(render("second"), "second");
+
+		// Same size, another modification time
+		fs::write(dir.join("page.md"), "33").unwrap();
+		fs::File::options().write(true).open(dir.join("page.md")).unwrap().set_modified(modified + Duration::from_secs(1)).unwrap();
+		assert_eq!(render("third"), "third");
+		assert_eq!(render("not rendered"), "third");
+	}
+}
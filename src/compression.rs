@@ -0,0 +1,164 @@
+// Response compression: Accept-Encoding negotiation, on-the-fly compression and precompressed files.
+
+use flate2::{read::GzEncoder, Compression};
+
+use std::io::{self, prelude::*};
+
+use crate::body::Body;
+use crate::config::CONFIG;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+	Brotli,
+	Zstd,
+	Gzip,
+}
+
+// Supported encodings, in order of preference when the client has no preference
+const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+	// Value of the Content-Encoding header
+	pub fn name(self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Zstd => "zstd",
+			Encoding::Gzip => "gzip",
+		}
+	}
+
+	// Extension of the precompressed files
+	pub fn extension(self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Zstd => "zst",
+			Encoding::Gzip => "gz",
+		}
+	}
+}
+
+// Parse an Accept-Encoding header (RFC 9110 section 12.5.3) and return the supported encodings that
+// the client accepts, the most preferred first
+pub fn negotiate(accept_encoding: &str) -> Vec<Encoding> {
+	// Quality value of each coding, "*" included
+	let mut qualities: Vec<(String, f32)> = vec![];
+
+	for item in accept_encoding.split(',') {
+		let mut params = item.split(';');
+		let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+
+		if coding.is_empty() {
+			continue;
+		}
+
+		let quality = params
+			.filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+			.find_map(|q| q.trim().parse::<f32>().ok())
+			.unwrap_or(1.0);
+
+		qualities.push((coding, quality));
+	}
+
+	let quality_of = |coding: &str| {
+		qualities.iter()
+			.find(|(c, _)| c == coding)
+			.or_else(|| qualities.iter().find(|(c, _)| c == "*"))
+			.map(|(_, q)| *q)
+			.unwrap_or(0.0)
+	};
+
+	let mut accepted: Vec<(Encoding, f32)> = ENCODINGS.iter()
+		.map(|e| (*e, quality_of(e.name())))
+		.filter(|(_, q)| *q > 0.0)
+		.collect();
+
+	// The sort is stable, encodings with the same quality keep the default order
+	accepted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+	accepted.into_iter()
+		.map(|(e, _)| e)
+		.collect()
+}
+
+// Whether it makes sense to compress a content of this MIME type (text-based formats)
+pub fn is_compressible(mime_type: &str) -> bool {
+	let essence = mime_type.split(';')
+		.next()
+		.unwrap_or("")
+		.trim()
+		.to_ascii_lowercase();
+
+	essence.starts_with("text/")
+		|| essence.ends_with("+xml")
+		|| essence.ends_with("+json")
+		|| matches!(essence.as_str(),
+			"application/javascript" | "application/json" | "application/xml" | "application/wasm" | "image/svg+xml")
+}
+
+// Wrap a reader so that the data gets compressed while it is read
+fn encoder(reader: Box<dyn Read + Send>, encoding: Encoding) -> io::Result<Box<dyn Read + Send>> {
+	let level = CONFIG.get().compression.level;
+
+	Ok(match encoding {
+		Encoding::Gzip => Box::new(GzEncoder::new(reader, Compression::new(level.min(9)))),
+		Encoding::Brotli => Box::new(brotli::CompressorReader::new(reader, 4096, level.min(11), 22)),
+		Encoding::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, level.clamp(1, 22) as i32)?),
+	})
+}
+
+// Compress a body. Content in memory is compressed immediately, so that its length is known,
+// files are compressed while they are sent.
+pub fn compress(body: Body, encoding: Encoding) -> io::Result<Body> {
+	match body {
+		Body::Bytes(b) => {
+			let mut compressed: Vec<u8> = vec![];
+			encoder(Body::Bytes(b).into_reader(), encoding)?.read_to_end(&mut compressed)?;
+
+			Ok(Body::Bytes(compressed))
+		},
+		body => Ok(Body::Stream { reader: encoder(body.into_reader(), encoding)?, len: None })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use Encoding::*;
+
+	#[test]
+	fn quality_values() {
+		assert_eq!(negotiate("gzip;q=0.5, br;q=0.8, zstd;q=0.1"), vec![Brotli, Gzip, Zstd]);
+		assert_eq!(negotiate("GZIP;Q=1, br ; q=0.9"), vec![Gzip, Brotli]);
+		assert_eq!(negotiate("gzip;q=abc"), vec![Gzip]);
+	}
+
+	#[test]
+	fn zero_quality_excludes() {
+		assert_eq!(negotiate("gzip;q=0, br"), vec![Brotli]);
+		assert_eq!(negotiate("gzip;q=0.000"), vec![]);
+	}
+
+	#[test]
+	fn ties_keep_the_default_order() {
+		assert_eq!(negotiate("gzip, zstd, br"), vec![Brotli, Zstd, Gzip]);
+		assert_eq!(negotiate("gzip;q=0.5, br;q=0.5"), vec![Brotli, Gzip]);
+	}
+
+	#[test]
+	fn wildcard() {
+		assert_eq!(negotiate("*"), vec![Brotli, Zstd, Gzip]);
+		// Explicit codings take precedence over "*"
+		assert_eq!(negotiate("*;q=0.5, gzip"), vec![Gzip, Brotli, Zstd]);
+		assert_eq!(negotiate("*, br;q=0"), vec![Zstd, Gzip]);
+		assert_eq!(negotiate("*;q=0"), vec![]);
+	}
+
+	#[test]
+	fn identity() {
+		// Identity is always available, it is sent when nothing else is accepted
+		assert_eq!(negotiate("identity;q=0, gzip"), vec![Gzip]);
+		assert_eq!(negotiate("identity;q=0"), vec![]);
+		assert_eq!(negotiate(""), vec![]);
+	}
+}
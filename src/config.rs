@@ -1,17 +1,45 @@
 extern crate toml;
-use serde_derive::Deserialize;
+use log::LevelFilter;
+use serde::de::DeserializeOwned;
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
 use crate::io_ops;
-use crate::args;
+use crate::tls;
 
 pub const DEFAULT_ADDRESS: &str = "127.0.0.1:80";
 pub const DEFAULT_THREADS: usize = 4;
 pub const DEFAULT_VERB: u8 = 2;
-pub const DEFAULT_404_PAGE_CONTENT: &str = "ERROR 404: Not found.";
 pub const DEFAULT_USE_TLS: bool = true;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: u64 = 5;
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+pub const DEFAULT_WRITE_TIMEOUT: u64 = 30;
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+pub const DEFAULT_SYMLINKS: SymlinkPolicy = SymlinkPolicy::InsideWww;
+pub const DEFAULT_HIDDEN_FILES: bool = false;
+pub const DEFAULT_ACCESS_LOG_FORMAT: AccessLogFormat = AccessLogFormat::Combined;
+pub const DEFAULT_LOG_ROTATION: Rotation = Rotation::Never;
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 0;
+pub const DEFAULT_LOG_KEEP: usize = 7;
+pub const DEFAULT_CACHE_MAX_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_COMPRESSION_ENABLED: bool = true;
+pub const DEFAULT_COMPRESSION_MIN_SIZE: u64 = 1024;
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
 
 // Root path: repo root
 pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+// Prefix of the environment variables that replace the keys of the [server] section (e.g.
+// WEBSERVER_THREADS for threads). WEBSERVER_CONFIG is the path of config.toml.
+pub const ENV_PREFIX: &str = "WEBSERVER_";
 pub const DEFAULT_WWW: &str = "www/";
 
 // Root path: WWW
@@ -20,9 +48,80 @@ pub const DEFAULT_PAGE_404_PATH: &str = "404.md";
 // Root path: WWW/style/
 pub const DEFAULT_MD_STYLE: &str = "default.scss";
 
-// Program's internal configuration loaded on server start
+// Program's internal configuration, loaded on server start and replaced when config.toml is reloaded
 lazy_static! {
-	pub static ref CONFIG: Config = Config::init(args::parse_args());
+	static ref ARGS: ParsedArgs = parse_args();
+	pub static ref CONFIG: ConfigHandle = ConfigHandle::new(Config::init(&ARGS));
+}
+
+// Command line arguments, they don't change when config.toml is reloaded
+pub fn args() -> &'static ParsedArgs {
+	&ARGS
+}
+
+#[cfg(not(test))]
+fn parse_args() -> ParsedArgs {
+	crate::args::parse_args()
+}
+
+// Unit tests have no command line: config.toml of the repository is used, with WWW in a temporary
+// directory where each test module creates the files it needs
+#[cfg(test)]
+fn parse_args() -> ParsedArgs {
+	let www_path = test_www_path();
+	std::fs::create_dir_all(&www_path).unwrap();
+
+	ParsedArgs {
+		verbosity: 0,
+		use_tls: false,
+		command: Command::Serve,
+		config_path: DEFAULT_CONFIG_PATH.into(),
+		address: None,
+		port: None,
+		threads: None,
+		www_path: Some(format!("{}/", www_path.display())),
+		title: None,
+		cert_path: None,
+		key_path: None
+	}
+}
+
+#[cfg(test)]
+pub fn test_www_path() -> std::path::PathBuf {
+	env::temp_dir().join("webserver-tests")
+}
+
+// Handle to the current configuration. Keep the Arc returned by get() to read consistent values
+// while config.toml is being reloaded.
+pub struct ConfigHandle {
+	current: RwLock<Arc<Config>>,
+}
+
+impl ConfigHandle {
+	fn new(config: Config) -> Self {
+		ConfigHandle { current: RwLock::new(Arc::new(config)) }
+	}
+
+	pub fn get(&self) -> Arc<Config> {
+		self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+
+	// Parse config.toml again and replace the current configuration, returns the old and the new one.
+	// If the file cannot be read or parsed, the current configuration is kept. Environment variables
+	// are read again too.
+	pub fn reload(&self) -> Result<(Arc<Config>, Arc<Config>), String> {
+		let new_config = Config::load(&ARGS)?;
+
+		let problems = new_config.validate();
+		if !problems.is_empty() {
+			return Err(problems.join("; "));
+		}
+
+		let new_config = Arc::new(new_config);
+		let old_config = std::mem::replace(&mut *self.current.write().unwrap_or_else(|e| e.into_inner()), new_config.clone());
+
+		Ok((old_config, new_config))
+	}
 }
 
 
@@ -30,42 +129,235 @@ lazy_static! {
 // final Config constant.
 // ParsedConfig and ParsedServer structs are used to parse the config.toml file.
 // ParsedArgs struct is used to parse the command line arguments.
+// Values are taken from the command line, then from the environment variables, then from config.toml,
+// then from the DEFAULT_* constants.
 
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 struct ParsedServer {
 	address: Option<String>,
 	threads: Option<usize>,
 	err404_path: Option<String>,
 	title: Option<String>,
-    www_path: Option<String>
+    www_path: Option<String>,
+	cert_path: Option<String>,
+	key_path: Option<String>,
+	max_body_size: Option<usize>,
+	keep_alive_timeout: Option<u64>,
+	request_timeout: Option<u64>,
+	write_timeout: Option<u64>,
+	max_requests_per_connection: Option<usize>,
+	symlinks: Option<SymlinkPolicy>,
+	hidden_files: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedCache {
+	max_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedCompression {
+	enabled: Option<bool>,
+	min_size: Option<u64>,
+	level: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedListing {
+	directories: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedAccessLog {
+	path: Option<String>,
+	format: Option<AccessLogFormat>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedLog {
+	path: Option<String>,
+	rotation: Option<Rotation>,
+	max_size: Option<u64>,
+	keep: Option<usize>,
+	filters: Option<HashMap<String, LevelFilter>>,
 }
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ParsedConfig {
 	server: Option<ParsedServer>,
+	cache: Option<ParsedCache>,
+	compression: Option<ParsedCompression>,
+	listing: Option<ParsedListing>,
+	// Keys are status codes
+	errors: Option<HashMap<String, String>>,
+	access_log: Option<ParsedAccessLog>,
+	log: Option<ParsedLog>,
 }
 
+#[derive(Clone)]
 pub struct ParsedArgs {
 	pub verbosity: u8,
 	pub use_tls: bool,
+	pub command: Command,
+	pub config_path: String,
+	// They replace the keys of the [server] section
+	pub address: Option<String>,
+	// Replaces the port of the address
+	pub port: Option<u16>,
+	pub threads: Option<usize>,
+	pub www_path: Option<String>,
+	pub title: Option<String>,
+	pub cert_path: Option<String>,
+	pub key_path: Option<String>,
 }
 
+// What the program has been asked to do
 #[derive(Clone, Debug)]
+pub enum Command {
+	// Run the webserver
+	Serve,
+	// Render the whole WWW directory into a directory
+	Build { out_path: String },
+	// Print the configuration, as it results from config.toml, the environment variables and the
+	// command line
+	PrintConfig,
+	// Validate the configuration and exit
+	CheckConfig,
+	// Create config.toml and WWW with the default content
+	Init,
+}
+
+// Serialized into the TOML format of config.toml (see --print-config)
+#[derive(Serialize, Clone, Debug)]
 pub struct Config {
+	#[serde(skip)]
+	pub command: Command,
+	// Path of config.toml
+	#[serde(skip)]
+	pub config_path: String,
 	pub server: Server,
+	pub cache: Cache,
+	pub compression: Compression,
+	pub listing: Listing,
+	#[serde(serialize_with = "serialize_error_pages")]
+	pub errors: Errors,
+	pub access_log: AccessLog,
+	pub log: Log,
+	#[serde(skip)]
 	pub printing: Printing,
+	// When config.toml has been read, the pages generated with its values may have changed since
+	#[serde(skip)]
+	pub loaded: SystemTime,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Clone, Debug)]
 pub struct Server {
 	pub address: String,
 	pub threads: usize,
-	pub err404_path: String,
 	pub title: String,
     pub www_path: String,
+	// Not a key of config.toml (see --no-tls), --print-config writes it as a comment
+	#[serde(skip)]
 	pub use_tls: bool,
-	
+	pub cert_path: Option<String>,
+	pub key_path: Option<String>,
+	pub max_body_size: usize,
+	pub keep_alive_timeout: u64,
+	// Seconds without data after which reading a request or sending a response is given up
+	pub request_timeout: u64,
+	pub write_timeout: u64,
+	pub max_requests_per_connection: usize,
+	pub symlinks: SymlinkPolicy,
+	// Whether files and directories whose name starts with a dot can be sent
+	pub hidden_files: bool,
+}
+
+// How symbolic links inside WWW are treated
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+	// Never followed
+	Deny,
+	// Followed only if they point to a file inside WWW
+	InsideWww,
+	// Always followed, even outside WWW
+	Follow,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Cache {
+	pub max_size: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Compression {
+	pub enabled: bool,
+	// Smaller contents are not compressed
+	pub min_size: u64,
+	pub level: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Listing {
+	// Directories (relative to WWW) whose content can be listed, subdirectories included
+	pub directories: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Errors {
+	// Pages (relative to WWW) sent with error status codes
+	pub pages: HashMap<u16, String>,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct AccessLog {
+	// Not relative to WWW, "-" is the standard output. None disables the access log.
+	pub path: Option<String>,
+	pub format: AccessLogFormat,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+	// Common Log Format
+	Common,
+	// Combined Log Format: Common Log Format, referrer and user agent
+	Combined,
+	// One JSON object per line
+	Json,
+}
+
+// Log files (server messages and access log)
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Log {
+	// File for the server messages, not relative to WWW. None means standard output and error.
+	pub path: Option<String>,
+	pub rotation: Rotation,
+	// Size in bytes after which the files are rotated, 0 disables size-based rotation
+	pub max_size: u64,
+	// Number of rotated files kept
+	pub keep: usize,
+	// Maximum level of the messages of some modules (e.g. "webserver::cache" or "rustls"), it
+	// replaces the verbosity level for them and their submodules
+	#[serde(serialize_with = "serialize_filters")]
+	pub filters: HashMap<String, LevelFilter>,
+}
+
+// Time-based rotation of the log files
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+	Never,
+	Hourly,
+	Daily,
 }
 
 #[derive(Clone, Debug)]
@@ -74,41 +366,361 @@ pub struct Printing {
 }
 
 impl Config {
-	pub fn init(args_config: ParsedArgs) -> Self {
+	// Exits if config.toml cannot be parsed, the logger is not ready yet so the error is printed
+	pub fn init(args_config: &ParsedArgs) -> Self {
+		Config::load(args_config).unwrap_or_else(|e| {
+			eprintln!("{}", format_problems(&args_config.config_path, &[e]));
+			std::process::exit(1);
+		})
+	}
 
-		let toml_file_config = Config::parse_pers_config();
+	// Read config.toml and merge it with the environment variables and the command line arguments
+	fn load(args_config: &ParsedArgs) -> Result<Self, String> {
+
+		let toml_file_config = Config::parse_pers_config(&args_config.config_path)?;
 
 		// Take a ParsedConfig instance and put default values on Nones
 		// ParsedConfig -> Config
-		Config {
-			server: {
-				let server = toml_file_config.server.unwrap();
-
-				Server {
-					address: server.address.unwrap_or(DEFAULT_ADDRESS.into()),
-					threads: server.threads.unwrap_or(DEFAULT_THREADS),
-					err404_path: server.err404_path.unwrap_or(DEFAULT_PAGE_404_PATH.into()),
-					title: server.title.unwrap_or("".into()),
-					www_path: server.www_path.unwrap_or(DEFAULT_WWW.into()),
-					use_tls: args_config.use_tls
+		let mut server = toml_file_config.server.unwrap_or_default();
+		server.apply_env()?;
+		server.apply_args(args_config);
+
+		// err404_path is used unless [errors] has a page for 404
+		let mut error_pages: HashMap<u16, String> = HashMap::new();
+		error_pages.insert(404, server.err404_path.take().unwrap_or_else(|| DEFAULT_PAGE_404_PATH.into()));
+
+		for (status, path) in toml_file_config.errors.unwrap_or_default() {
+			match status.parse::<u16>() {
+				Ok(s @ 400..=599) => error_pages.insert(s, path),
+				_ => return Err(format!("Invalid key in [errors]: \"{}\" is not an error status code (400-599)", status)),
+			};
+		}
+
+		Ok(Config {
+			command: args_config.command.clone(),
+			config_path: args_config.config_path.clone(),
+			server: Server {
+				address: server.address.unwrap_or_else(|| DEFAULT_ADDRESS.into()),
+				threads: server.threads.unwrap_or(DEFAULT_THREADS),
+				title: server.title.unwrap_or_default(),
+				www_path: server.www_path.unwrap_or_else(|| DEFAULT_WWW.into()),
+				use_tls: args_config.use_tls,
+				cert_path: server.cert_path,
+				key_path: server.key_path,
+				max_body_size: server.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+				keep_alive_timeout: server.keep_alive_timeout.unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT),
+				request_timeout: server.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+				write_timeout: server.write_timeout.unwrap_or(DEFAULT_WRITE_TIMEOUT),
+				max_requests_per_connection: server.max_requests_per_connection.unwrap_or(DEFAULT_MAX_REQUESTS_PER_CONNECTION),
+				symlinks: server.symlinks.unwrap_or(DEFAULT_SYMLINKS),
+				hidden_files: server.hidden_files.unwrap_or(DEFAULT_HIDDEN_FILES),
+			},
+			cache: {
+				let max_size = toml_file_config.cache.and_then(|c| c.max_size);
+
+				Cache {
+					max_size: max_size.unwrap_or(DEFAULT_CACHE_MAX_SIZE)
+				}
+			},
+			compression: {
+				let compression = toml_file_config.compression.as_ref();
+
+				Compression {
+					enabled: compression.and_then(|c| c.enabled).unwrap_or(DEFAULT_COMPRESSION_ENABLED),
+					min_size: compression.and_then(|c| c.min_size).unwrap_or(DEFAULT_COMPRESSION_MIN_SIZE),
+					level: compression.and_then(|c| c.level).unwrap_or(DEFAULT_COMPRESSION_LEVEL)
+				}
+			},
+			listing: Listing {
+				directories: toml_file_config.listing
+					.and_then(|l| l.directories)
+					.unwrap_or_default()
+			},
+			errors: Errors {
+				pages: error_pages
+			},
+			access_log: {
+				let access_log = toml_file_config.access_log;
+
+				AccessLog {
+					format: access_log.as_ref().and_then(|a| a.format).unwrap_or(DEFAULT_ACCESS_LOG_FORMAT),
+					path: access_log.and_then(|a| a.path)
+				}
+			},
+			log: {
+				let mut log = toml_file_config.log;
+
+				Log {
+					rotation: log.as_ref().and_then(|l| l.rotation).unwrap_or(DEFAULT_LOG_ROTATION),
+					max_size: log.as_ref().and_then(|l| l.max_size).unwrap_or(DEFAULT_LOG_MAX_SIZE),
+					keep: log.as_ref().and_then(|l| l.keep).unwrap_or(DEFAULT_LOG_KEEP),
+					filters: log.as_mut().and_then(|l| l.filters.take()).unwrap_or_default(),
+					path: log.and_then(|l| l.path)
 				}
 			},
 			printing: Printing {
 				verbosity: args_config.verbosity
+			},
+			loaded: SystemTime::now()
+		})
+	}
+
+	// Check the values that can be parsed but cannot be used, returns the problems found
+	pub fn validate(&self) -> Vec<String> {
+		let mut problems: Vec<String> = vec![];
+		let server = &self.server;
+		let www_dir = Path::new(&server.www_path);
+
+		if !www_dir.is_dir() {
+			problems.push(format!("www_path: \"{}\" is not a directory", server.www_path));
+		}
+
+		if server.address.to_socket_addrs().map_or(true, |mut a| a.next().is_none()) {
+			problems.push(format!("address: \"{}\" is not a valid socket address (e.g. \"127.0.0.1:80\")", server.address));
+		}
+
+		if server.threads == 0 {
+			problems.push("threads: it must be greater than 0".into());
+		}
+
+		if server.request_timeout == 0 {
+			problems.push("request_timeout: it must be greater than 0".into());
+		}
+
+		if server.write_timeout == 0 {
+			problems.push("write_timeout: it must be greater than 0".into());
+		}
+
+		let mut error_pages: Vec<(&u16, &String)> = self.errors.pages.iter().collect();
+		error_pages.sort();
+
+		for (status, path) in error_pages {
+			// Without the default 404 page the built-in one is used
+			if *status == 404 && path.trim_start_matches('/') == DEFAULT_PAGE_404_PATH {
+				continue;
+			}
+
+			if !www_dir.join(path.trim_start_matches('/')).is_file() {
+				problems.push(format!("[errors] {}: \"{}\" is not a file in www_path", status, path));
+			}
+		}
+
+		if server.use_tls {
+			if let Err(e) = tls::load_server_config(server.cert_path.as_ref(), server.key_path.as_ref()) {
+				problems.push(format!("cert_path and key_path: {}", e));
 			}
 		}
+
+		problems
 	}
 
 	// Returns parsed config.toml (persistent config)
-	// Call this function only once in the whole program
-	fn parse_pers_config() -> ParsedConfig {
-		let config_file_content = io_ops::get_config_file();
+	fn parse_pers_config(path: &str) -> Result<ParsedConfig, String> {
+		let config_file_content = io_ops::get_config_file(path)
+			.map_err(|_| format!("Couldn't open/read {}", path))?;
+
+		toml::from_str(&config_file_content)
+			.map_err(|e| format!("Couldn't parse {}: {}", path, parse_error_message(&config_file_content, &e)))
+	}
+}
+
+// Message of a parsing error. The position that the parser gives for unknown keys is not the one of
+// the key, so the key is looked for in its table. If it cannot be found there (e.g. in inline
+// tables), the message of the parser is used as it is.
+fn parse_error_message(content: &str, error: &toml::de::Error) -> String {
+	let message = error.to_string();
+
+	let key = match message.strip_prefix("unknown field `").and_then(|m| m.split('`').next()) {
+		Some(k) => k,
+		None => return message,
+	};
+
+	// Text of the error without the position
+	let text = message.rfind(" at line ").map_or(message.as_str(), |i| &message[..i]);
+
+	// Keys of the root table have no "for key" part, they are before the first table header
+	let table = text.rsplit_once(" for key `").map(|(_, t)| t.trim_end_matches('`'));
+	let table_start = match table {
+		Some(t) => content.lines().position(|l| l.trim() == format!("[{}]", t)).map(|i| i + 1),
+		None => Some(0),
+	};
+
+	let key_position = table_start.and_then(|start| {
+		content.lines()
+			.enumerate()
+			.skip(start)
+			.take_while(|(_, line)| !line.trim_start().starts_with('['))
+			.find_map(|(i, line)| {
+				let trimmed = line.trim_start();
+				let rest = trimmed.strip_prefix(key)
+					.or_else(|| trimmed.strip_prefix(&format!("\"{}\"", key)))
+					.or_else(|| trimmed.strip_prefix(&format!("'{}'", key)))?;
+
+				rest.trim_start().starts_with('=').then(|| (i + 1, line.len() - trimmed.len() + 1))
+			})
+	});
+
+	match key_position {
+		Some((line, column)) => format!("{} at line {} column {}", text, line, column),
+		None => message.clone(),
+	}
+}
+
+impl ParsedServer {
+	// Replace the keys set through the environment variables
+	fn apply_env(&mut self) -> Result<(), String> {
+		set_from_env(&mut self.address, "address")?;
+		set_from_env(&mut self.threads, "threads")?;
+		set_from_env(&mut self.err404_path, "err404_path")?;
+		set_from_env(&mut self.title, "title")?;
+		set_from_env(&mut self.www_path, "www_path")?;
+		set_from_env(&mut self.cert_path, "cert_path")?;
+		set_from_env(&mut self.key_path, "key_path")?;
+		set_from_env(&mut self.max_body_size, "max_body_size")?;
+		set_from_env(&mut self.keep_alive_timeout, "keep_alive_timeout")?;
+		set_from_env(&mut self.request_timeout, "request_timeout")?;
+		set_from_env(&mut self.write_timeout, "write_timeout")?;
+		set_from_env(&mut self.max_requests_per_connection, "max_requests_per_connection")?;
+		set_from_env(&mut self.symlinks, "symlinks")?;
+		set_from_env(&mut self.hidden_files, "hidden_files")?;
+
+		Ok(())
+	}
+
+	// Replace the keys set through the command line
+	fn apply_args(&mut self, args_config: &ParsedArgs) {
+		if args_config.address.is_some() {
+			self.address = args_config.address.clone();
+		}
+
+		if args_config.threads.is_some() {
+			self.threads = args_config.threads;
+		}
 
-		let config_file_content = config_file_content.expect(&format!("Couldn't open/read {}", DEFAULT_CONFIG_PATH));
+		if args_config.www_path.is_some() {
+			self.www_path = args_config.www_path.clone();
+		}
+
+		if args_config.title.is_some() {
+			self.title = args_config.title.clone();
+		}
+
+		if args_config.cert_path.is_some() {
+			self.cert_path = args_config.cert_path.clone();
+		}
+
+		if args_config.key_path.is_some() {
+			self.key_path = args_config.key_path.clone();
+		}
+
+		// The port replaces the one of the address, wherever it comes from
+		if let Some(port) = args_config.port {
+			let address = self.address.take().unwrap_or_else(|| DEFAULT_ADDRESS.into());
+
+			self.address = Some(match address.rsplit_once(':') {
+				Some((host, _)) => format!("{}:{}", host, port),
+				None => format!("{}:{}", address, port),
+			});
+		}
+	}
+}
+
+// Readable list of problems found in the configuration
+pub fn format_problems(config_path: &str, problems: &[String]) -> String {
+	let mut text = format!("Invalid configuration ({}):", config_path);
+
+	for problem in problems {
+		text.push_str(&format!("\n - {}", problem));
+	}
+
+	text
+}
+
+// Replace a key with the value of its environment variable (e.g. WEBSERVER_TITLE for title), if set.
+// The value is read as a string or, if that is not valid for the key, as a TOML value (e.g. 8 or true).
+fn set_from_env<T: DeserializeOwned>(key: &mut Option<T>, name: &str) -> Result<(), String> {
+	let var_name = format!("{}{}", ENV_PREFIX, name.to_uppercase());
+
+	let value = match env::var(&var_name) {
+		Ok(v) => v,
+		Err(env::VarError::NotPresent) => return Ok(()),
+		Err(e) => return Err(format!("Invalid value of {}: {}", var_name, e)),
+	};
+
+	let parsed = toml::Value::String(value.clone()).try_into::<T>()
+		.or_else(|_| {
+			toml::from_str::<HashMap<String, T>>(&format!("value = {}", value))
+				.map(|mut v| v.remove("value").unwrap())
+		})
+		.map_err(|_| format!("Invalid value of {}: \"{}\"", var_name, value))?;
+
+	*key = Some(parsed);
+	Ok(())
+}
+
+// Error pages as in config.toml, status codes are the keys
+fn serialize_error_pages<S: Serializer>(errors: &Errors, serializer: S) -> Result<S::Ok, S::Error> {
+	let pages: BTreeMap<String, &String> = errors.pages
+		.iter()
+		.map(|(status, path)| (status.to_string(), path))
+		.collect();
+
+	serializer.collect_map(pages)
+}
+
+// Levels as in config.toml (e.g. "debug")
+fn serialize_filters<S: Serializer>(filters: &HashMap<String, LevelFilter>, serializer: S) -> Result<S::Ok, S::Error> {
+	let filters: BTreeMap<&String, String> = filters
+		.iter()
+		.map(|(module, level)| (module, level.as_str().to_lowercase()))
+		.collect();
+
+	serializer.collect_map(filters)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn error_message(content: &str) -> String {
+		let error = toml::from_str::<ParsedConfig>(content).err().unwrap();
+		parse_error_message(content, &error)
+	}
+
+	#[test]
+	fn unknown_key_in_table() {
+		let message = error_message("[server]\naddress = \"127.0.0.1:80\"\n  adress = \"x\"\n\n[cache]\n");
+
+		assert!(message.starts_with("unknown field `adress`"));
+		assert!(message.ends_with("at line 3 column 3"));
+	}
+
+	#[test]
+	fn unknown_top_level_key() {
+		let message = error_message("\nbogus = 1\n\n[server]\naddress = \"x\"\n");
+
+		assert!(message.starts_with("unknown field `bogus`"));
+		assert!(message.ends_with("at line 2 column 1"));
+	}
+
+	#[test]
+	fn unknown_key_in_inline_table() {
+		let content = "server = { address = \"x\", bogus = 1 }\n";
+		let error = toml::from_str::<ParsedConfig>(content).err().unwrap();
+
+		// The key cannot be found, the position given by the parser is kept
+		assert!(error.line_col().is_some());
+		assert_eq!(parse_error_message(content, &error), error.to_string());
+	}
 
-		let config: ParsedConfig = toml::from_str(&config_file_content)
-			.expect(&format!("Couldn't parse {}: bad syntax.", DEFAULT_CONFIG_PATH));
+	#[test]
+	fn unknown_subtable() {
+		let content = "[server]\naddress = \"x\"\n\n[server.bogus]\nkey = 1\n";
+		let error = toml::from_str::<ParsedConfig>(content).err().unwrap();
+		let message = parse_error_message(content, &error);
 
-		config
+		assert!(message.starts_with("unknown field `bogus`"));
+		assert_eq!(message, error.to_string());
+		assert!(message.contains(" at line "));
 	}
 }
@@ -15,50 +15,87 @@ www/
 use std::path::Path;
 use std::fs;
 
-use crate::config::CONFIG;
-use crate::printing::{print_msg, MsgType};
+use crate::config::{Config, ParsedArgs, CONFIG};
 
-// Check if all the files needed to run are available
-// true = ok
-// false = files are missing
-pub fn check_files() {
+// Create the files and directories of WWW that are missing, returns the created paths
+fn create_www_files(www_path: &str) -> Result<Vec<String>, String> {
 	// Directories always end with "/", otherwise they will be recognized as files
 	// Specify a directory only if it needs to be empty
-	// The first tuple element represents the file path
+	// The first tuple element represents the file path (relative to WWW)
 	// The second tuple element represents the file content
-	let www_path = &CONFIG.server.www_path;
-	let base: [(String, Option<String>); 5] = [
-		("config.toml".into(), Some(include_str!("../config.toml").into())),
-		(format!("{}index.md", www_path), Some("Hello World!".into())),
-		(format!("{}favicon.ico", www_path), None),
-		(format!("{}style/default.scss", www_path), Some(include_str!("../www/style/default.scss").into())),
-		(format!("{}style/font/", www_path), None),
+	let base: [(&str, Option<&str>); 4] = [
+		("index.md", Some("Hello World!")),
+		("favicon.ico", None),
+		("style/default.scss", Some(include_str!("../www/style/default.scss"))),
+		("style/font/", None),
 	];
 
-	for entity in base.iter() {
-		let path = &entity.0;
-		let content = &entity.1;
-
-		// Check if the entity exists (can be anything: file, directory, etc...)
-		if !Path::new(path).exists() {
-			if path.ends_with("/") {
-				// Create a directory and all of its parent components if they are missing
-				if let Err(_) = fs::create_dir_all(path) {
-					print_msg(format!("Unable to create {}", path), MsgType::Error);
-					std::process::exit(1);
-				}
-			} else {
-				if let Err(_) = fs::File::create(path) {
-					print_msg(format!("Unable to create {}", path), MsgType::Error);
-					std::process::exit(1);
-				}
-
-				// Write file if needed
-				// If the program could create the file, it's likely to also be able to write it
-				if let Some(c) = content {
-					fs::write(path, c).unwrap();
-				}
-			}
+	let mut created: Vec<String> = vec![];
+
+	for (rel_path, content) in base.iter() {
+		let path = Path::new(www_path).join(rel_path);
+
+		if create_entity(&path, rel_path.ends_with('/'), *content)? {
+			created.push(path.display().to_string());
 		}
 	}
-}
\ No newline at end of file
+
+	Ok(created)
+}
+
+// Create a file (with its content, if any) or a directory and all of its parent components, unless
+// it already exists. Returns whether it has been created.
+fn create_entity(path: &Path, is_dir: bool, content: Option<&str>) -> Result<bool, String> {
+	// Check if the entity exists (can be anything: file, directory, etc...)
+	if path.exists() {
+		return Ok(false);
+	}
+
+	let result = if is_dir {
+		fs::create_dir_all(path)
+	} else {
+		path.parent()
+			.map_or(Ok(()), fs::create_dir_all)
+			.and_then(|_| fs::write(path, content.unwrap_or_default()))
+	};
+
+	result
+		.map(|_| true)
+		.map_err(|e| format!("Unable to create {}: {}", path.display(), e))
+}
+
+// Check if all the files needed to run are available, the missing ones are created
+pub fn check_files() {
+	if let Err(e) = create_www_files(&CONFIG.get().server.www_path) {
+		error!("{}", e);
+		std::process::exit(1);
+	}
+}
+
+// Create config.toml with the default content, then the files of WWW (see the init subcommand).
+// The logger is not set up, so messages are printed.
+pub fn init(args_config: &ParsedArgs) -> Result<(), String> {
+	let config_path = Path::new(&args_config.config_path);
+	let mut config_content = include_str!("../config.toml").to_string();
+
+	// The new config.toml points to the WWW directory set through the command line
+	if let Some(www_path) = &args_config.www_path {
+		let value = toml::Value::String(www_path.clone());
+		config_content = config_content.replace("www_path = \"www/\"", &format!("www_path = {}", value));
+	}
+
+	if create_entity(config_path, false, Some(&config_content))? {
+		println!("Created {}", config_path.display());
+	} else {
+		println!("{} already exists, not modified", config_path.display());
+	}
+
+	// www_path may be changed through the command line or the environment variables
+	let config = Config::init(args_config);
+
+	for path in create_www_files(&config.server.www_path)? {
+		println!("Created {}", path);
+	}
+
+	Ok(())
+}
@@ -1,8 +1,10 @@
 use comrak::{ComrakOptions, ComrakExtensionOptions, ComrakParseOptions, ComrakRenderOptions, markdown_to_html};
 use regex::Regex;
 
-use crate::io_ops::get_file_content;
-use crate::printing::{print_msg, MsgType};
+use std::io;
+
+use crate::cache;
+use crate::io_ops::{get_file_content, FileError};
 use crate::config::{self, CONFIG};
 
 // See https://docs.rs/comrak/latest/comrak/struct.ComrakOptions.html
@@ -41,7 +43,7 @@ const COMRAK_OPTIONS: ComrakOptions = {
 fn build_html_document(md_fc: &str, page_title: &str, stylesheets: Vec<String>) -> String {
 
 	let mut html_body = markdown_to_html(
-		&md_fc,
+		md_fc,
 		&COMRAK_OPTIONS
 	);
 
@@ -50,7 +52,7 @@ fn build_html_document(md_fc: &str, page_title: &str, stylesheets: Vec<String>)
 
 	// Build title
 	let title = {
-		if page_title != "" {
+		if !page_title.is_empty() {
 			format!("<title> {} </title>", page_title)
 		} else {
 			String::new()
@@ -78,11 +80,10 @@ fn generate_title(path: &str) -> String {
 	// Get the last part of path (filename) without file extension
 	let mut page_title = path
 		.split('/')
-		.last()
+		.next_back()
 		.unwrap()
-		.to_owned()
 		.split('.')
-		.nth(0)
+		.next()
 		.unwrap()
 		.to_owned();
 
@@ -92,48 +93,51 @@ fn generate_title(path: &str) -> String {
 
 	// Capitalize the first letter
 	if let Some(r) = page_title.get_mut(0..1) {
-		r.to_uppercase();
+		r.make_ascii_uppercase();
 	}
 
 	page_title
 }
 
-// "Compile" the markdown file into an HTML file.
+// "Compile" the markdown file into an HTML file, the result is cached.
 // file_path does not include WWW
-pub fn md_to_html(file_path: &String) -> Result<String, ()> {
+pub fn md_to_html(file_path: &str) -> Result<String, FileError> {
+	cache::get_or_render(file_path, || render_md_file(file_path))
+}
 
-	let file_content_bytes = get_file_content(&file_path);
+fn render_md_file(file_path: &str) -> Result<String, FileError> {
 
-	if let Err(_) = file_content_bytes {
-		// File couldn't be read
-		return Err(());
-	}
+	let file_content_bytes = get_file_content(file_path)?;
 
-	// Get file content as string from bytes
-	let file_content_str = String::from_utf8(file_content_bytes.unwrap());
+	// Get file content as string from bytes, the file may be corrupted
+	let file_content_str = String::from_utf8(file_content_bytes)
+		.map_err(|e| FileError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
 
-	// The file may be corrupted
-	if let Err(_) = file_content_str {
-		return Err(());
-	}
+	debug!("Translating markdown file {} into HTML...", file_path);
+
+	// Markdown file translated in HTML
+	Ok(build_site_page(&file_content_str, &generate_title(file_path)))
+}
 
-	print_msg(format!("Translating markdown file {} into HTML...", file_path), MsgType::Info);
+// Build an HTML page of the website from Markdown, with the site title and stylesheet.
+// name is added to the site title (e.g. "My website | name")
+pub fn build_site_page(md_fc: &str, name: &str) -> String {
 
 	// Empty title if not specified in config.toml
 	let page_title = {
-		if !CONFIG.server.title.is_empty() {
-			format!("{} | {}", CONFIG.server.title, generate_title(&file_path))
+		if !CONFIG.get().server.title.is_empty() {
+			format!("{} | {}", CONFIG.get().server.title, name)
 		} else {
 			"".into()
 		}
 	};
 
-	// Markdown file translated in HTML
-	let html_translation = build_html_document(
-		&file_content_str.unwrap(),
-		&page_title,
-		vec![config::DEFAULT_MD_STYLE.to_owned()],
-	);
+	build_html_document(md_fc, &page_title, vec![config::DEFAULT_MD_STYLE.to_owned()])
+}
+
+// Built-in page for error status codes, used when no page is configured for the status code
+pub fn default_error_page(status_code: u16, reason: &str) -> String {
+	let heading = format!("{} {}", status_code, reason);
 
-	Ok(html_translation)
-}
\ No newline at end of file
+	build_site_page(&format!("# {}\n\n[Go to the home page](/)\n", heading), &heading)
+}
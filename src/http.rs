@@ -1,86 +1,979 @@
-use http::{Request, Response, request::Builder};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use http::{response, Method, Request, Response, Uri, Version};
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, DATE, EXPECT, HOST, SERVER, TRANSFER_ENCODING};
 
+use rustls::{Session, StreamOwned};
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, prelude::*, BufReader};
 use std::net::TcpStream;
-use std::io::prelude::*;
+use std::time::SystemTime;
+
+use crate::body::Body;
+
+// Value of the Server header
+const SERVER_NAME: &str = concat!("webserver/", env!("CARGO_PKG_VERSION"));
+
+// Limits applied while reading the request head
+const MAX_REQUEST_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADER_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS_SIZE: usize = 32 * 1024;
+const MAX_HEADERS_COUNT: usize = 100;
+
+// Maximum number of ranges in a Range header, larger lists are ignored
+const MAX_RANGES: usize = 64;
+
+// Errors that may occur while reading a request from a stream
+#[derive(Debug)]
+pub enum RequestError {
+	// The client closed the connection before sending anything
+	Closed,
+	// The stream could not be read (timeout, reset, TLS error, etc...)
+	Io(io::Error),
+	// 400, the message explains what is wrong with the request
+	BadRequest(&'static str),
+	// 414
+	UriTooLong,
+	// 431
+	HeadersTooLarge,
+	// 413
+	PayloadTooLarge,
+	// 501, used for unknown transfer codings
+	NotImplemented,
+	// 505
+	VersionNotSupported,
+}
+
+impl RequestError {
+	// Status code of the response sent back to the client, None if no response should be sent
+	pub fn status(&self) -> Option<u16> {
+		match self {
+			RequestError::Closed | RequestError::Io(_) => None,
+			RequestError::BadRequest(_) => Some(400),
+			RequestError::UriTooLong => Some(414),
+			RequestError::HeadersTooLarge => Some(431),
+			RequestError::PayloadTooLarge => Some(413),
+			RequestError::NotImplemented => Some(501),
+			RequestError::VersionNotSupported => Some(505),
+		}
+	}
+}
+
+impl fmt::Display for RequestError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RequestError::Closed => write!(f, "connection closed by the client"),
+			RequestError::Io(e) => write!(f, "{}", e),
+			RequestError::BadRequest(m) => write!(f, "bad request: {}", m),
+			RequestError::UriTooLong => write!(f, "request line too long"),
+			RequestError::HeadersTooLarge => write!(f, "request header fields too large"),
+			RequestError::PayloadTooLarge => write!(f, "request body too large"),
+			RequestError::NotImplemented => write!(f, "unsupported transfer coding"),
+			RequestError::VersionNotSupported => write!(f, "HTTP version not supported"),
+		}
+	}
+}
+
+// Read a line terminated by LF (the CR before it is optional) and return it without the terminator.
+// too_long is returned as error if the line exceeds max_len bytes.
+// Ok(None) means that the stream ended before any byte of the line was read.
+fn read_line<R: BufRead>(reader: &mut R, max_len: usize, too_long: RequestError) -> Result<Option<Vec<u8>>, RequestError> {
+	let mut line: Vec<u8> = vec![];
+
+	// Read at most max_len + 2 bytes to leave room for CRLF
+	reader.by_ref()
+		.take(max_len as u64 + 2)
+		.read_until(b'\n', &mut line)
+		.map_err(RequestError::Io)?;
+
+	if line.last() != Some(&b'\n') {
+		if line.is_empty() {
+			return Ok(None);
+		} else if line.len() > max_len {
+			return Err(too_long);
+		} else {
+			return Err(RequestError::BadRequest("unexpected end of the request"));
+		}
+	}
+
+	line.pop();
+	if line.last() == Some(&b'\r') {
+		line.pop();
+	}
+
+	if line.len() > max_len {
+		return Err(too_long);
+	}
+
+	// A bare CR is not allowed anywhere in the head
+	if line.contains(&b'\r') {
+		return Err(RequestError::BadRequest("bare CR in the request head"));
+	}
+
+	Ok(Some(line))
+}
+
+// Parse the HTTP-version part of the request line
+fn parse_version(version: &[u8]) -> Result<Version, RequestError> {
+	match version {
+		b"HTTP/1.1" => Ok(Version::HTTP_11),
+		b"HTTP/1.0" => Ok(Version::HTTP_10),
+		[b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+			Err(RequestError::VersionNotSupported)
+		},
+		_ => Err(RequestError::BadRequest("invalid HTTP version"))
+	}
+}
+
+// Characters allowed in a header field name (token, see RFC 9110 section 5.6.2)
+fn is_token_char(c: u8) -> bool {
+	c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+// Remove leading and trailing optional whitespace (SP and HTAB)
+pub fn trim_ows(mut value: &[u8]) -> &[u8] {
+	while let [b' ' | b'\t', rest @ ..] = value {
+		value = rest;
+	}
+
+	while let [rest @ .., b' ' | b'\t'] = value {
+		value = rest;
+	}
+
+	value
+}
+
+// Read header (or trailer) fields until the empty line, obs-fold continuation lines are joined
+// to the previous value.
+// fields_size is the size of the fields read so far in the same request, it gets updated.
+fn read_fields<R: BufRead>(reader: &mut R, fields_size: &mut usize) -> Result<HeaderMap, RequestError> {
+	let mut fields: Vec<(HeaderName, Vec<u8>)> = vec![];
+
+	loop {
+		let line = read_line(reader, MAX_HEADER_LINE_LEN, RequestError::HeadersTooLarge)?
+			.ok_or(RequestError::BadRequest("unexpected end of the request"))?;
+
+		if line.is_empty() {
+			break;
+		}
+
+		*fields_size += line.len();
+		if *fields_size > MAX_HEADERS_SIZE {
+			return Err(RequestError::HeadersTooLarge);
+		}
+
+		// Obsolete line folding, replace it with a single SP
+		if line[0] == b' ' || line[0] == b'\t' {
+			let last_value = &mut fields.last_mut()
+				.ok_or(RequestError::BadRequest("whitespace before the first header field"))?
+				.1;
+
+			last_value.push(b' ');
+			last_value.extend_from_slice(trim_ows(&line));
+			continue;
+		}
+
+		if fields.len() == MAX_HEADERS_COUNT {
+			return Err(RequestError::HeadersTooLarge);
+		}
+
+		let colon = line.iter()
+			.position(|c| *c == b':')
+			.ok_or(RequestError::BadRequest("header field without colon"))?;
+		let (name, value) = (&line[..colon], &line[colon + 1..]);
+
+		// No whitespace is allowed between the field name and the colon
+		if name.is_empty() || !name.iter().all(|c| is_token_char(*c)) {
+			return Err(RequestError::BadRequest("invalid header field name"));
+		}
+
+		let name = HeaderName::from_bytes(name)
+			.map_err(|_| RequestError::BadRequest("invalid header field name"))?;
+
+		fields.push((name, trim_ows(value).to_vec()));
+	}
+
+	let mut headers = HeaderMap::new();
+
+	for (name, value) in fields {
+		let value = HeaderValue::from_bytes(&value)
+			.map_err(|_| RequestError::BadRequest("invalid header field value"))?;
+
+		headers.append(name, value);
+	}
+
+	Ok(headers)
+}
+
+// Read and parse the request head (request line and header fields) from a stream, see RFC 9112.
+// The body is not read.
+fn parse_request_head<R: BufRead>(reader: &mut R) -> Result<Request<()>, RequestError> {
+
+	// Request line, empty lines before it must be ignored
+	let request_line = loop {
+		match read_line(reader, MAX_REQUEST_LINE_LEN, RequestError::UriTooLong)? {
+			None => return Err(RequestError::Closed),
+			Some(l) if l.is_empty() => continue,
+			Some(l) => break l,
+		}
+	};
+
+	// method SP request-target SP HTTP-version
+	let parts: Vec<&[u8]> = request_line.split(|c| *c == b' ').collect();
+
+	if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+		return Err(RequestError::BadRequest("malformed request line"));
+	}
+
+	let method = Method::from_bytes(parts[0])
+		.map_err(|_| RequestError::BadRequest("invalid method"))?;
+	let uri = Uri::try_from(parts[1])
+		.map_err(|_| RequestError::BadRequest("invalid request target"))?;
+	let version = parse_version(parts[2])?;
+
+	let mut headers_size: usize = 0;
+	let headers = read_fields(reader, &mut headers_size)?;
+
+	// HTTP/1.1 requests must have exactly one Host header
+	if version == Version::HTTP_11 && headers.get_all(HOST).iter().count() != 1 {
+		return Err(RequestError::BadRequest("missing or duplicated Host header"));
+	}
+
+	let mut request = Request::new(());
+	*request.method_mut() = method;
+	*request.uri_mut() = uri;
+	*request.version_mut() = version;
+	*request.headers_mut() = headers;
+
+	Ok(request)
+}
+
+// How the request body is delimited
+enum BodyLength {
+	None,
+	Fixed(usize),
+	Chunked,
+}
+
+// Find out the body length from Transfer-Encoding and Content-Length (RFC 9112 section 6.3)
+fn get_body_length(head: &Request<()>) -> Result<BodyLength, RequestError> {
+	let headers = head.headers();
+
+	if headers.contains_key(TRANSFER_ENCODING) {
+		// Both headers may be used to smuggle requests, refuse them
+		if headers.contains_key(CONTENT_LENGTH) {
+			return Err(RequestError::BadRequest("both Transfer-Encoding and Content-Length are present"));
+		}
+
+		if head.version() == Version::HTTP_10 {
+			return Err(RequestError::BadRequest("Transfer-Encoding in an HTTP/1.0 request"));
+		}
+
+		// Transfer codings are listed in the order they were applied, only chunked is supported
+		let mut codings: Vec<Vec<u8>> = vec![];
+		for value in headers.get_all(TRANSFER_ENCODING) {
+			for coding in value.as_bytes().split(|c| *c == b',') {
+				let coding = trim_ows(coding);
+
+				if !coding.is_empty() {
+					codings.push(coding.to_ascii_lowercase());
+				}
+			}
+		}
+
+		return match codings.as_slice() {
+			[coding] if coding == b"chunked" => Ok(BodyLength::Chunked),
+			[.., last] if last == b"chunked" => Err(RequestError::NotImplemented),
+			[] => Err(RequestError::BadRequest("empty Transfer-Encoding")),
+			_ => Err(RequestError::BadRequest("the final transfer coding is not chunked")),
+		};
+	}
+
+	// Repeated Content-Length values (either as list or as multiple headers) must be identical
+	let mut length: Option<usize> = None;
+	for value in headers.get_all(CONTENT_LENGTH) {
+		for item in value.as_bytes().split(|c| *c == b',') {
+			let item = trim_ows(item);
+
+			if item.is_empty() || !item.iter().all(|c| c.is_ascii_digit()) {
+				return Err(RequestError::BadRequest("invalid Content-Length"));
+			}
+
+			let parsed: usize = std::str::from_utf8(item)
+				.ok()
+				.and_then(|i| i.parse().ok())
+				.ok_or(RequestError::PayloadTooLarge)?;
+
+			if length.is_some() && length != Some(parsed) {
+				return Err(RequestError::BadRequest("conflicting Content-Length values"));
+			}
+
+			length = Some(parsed);
+		}
+	}
+
+	match length {
+		Some(0) | None => Ok(BodyLength::None),
+		Some(l) => Ok(BodyLength::Fixed(l)),
+	}
+}
+
+// Read a chunked body, trailer fields are appended to headers
+fn read_chunked_body<R: BufRead>(reader: &mut R, headers: &mut HeaderMap, max_size: usize) -> Result<Vec<u8>, RequestError> {
+	let mut body: Vec<u8> = vec![];
+
+	loop {
+		// chunk-size [ chunk-ext ] CRLF, extensions are ignored
+		let line = read_line(reader, MAX_HEADER_LINE_LEN, RequestError::BadRequest("chunk size line too long"))?
+			.ok_or(RequestError::BadRequest("unexpected end of the request"))?;
+		let size_str = line.split(|c| *c == b';')
+			.next()
+			.map(trim_ows)
+			.unwrap_or_default();
+
+		if size_str.is_empty() || !size_str.iter().all(|c| c.is_ascii_hexdigit()) {
+			return Err(RequestError::BadRequest("invalid chunk size"));
+		}
+
+		let size = std::str::from_utf8(size_str)
+			.ok()
+			.and_then(|s| usize::from_str_radix(s, 16).ok())
+			.ok_or(RequestError::PayloadTooLarge)?;
+
+		// Last chunk
+		if size == 0 {
+			break;
+		}
+
+		// A chunk size near usize::MAX must not overflow the total
+		let end = body.len()
+			.checked_add(size)
+			.filter(|n| *n <= max_size)
+			.ok_or(RequestError::PayloadTooLarge)?;
+
+		let start = body.len();
+		body.resize(end, 0);
+		reader.read_exact(&mut body[start..]).map_err(|e| {
+			if e.kind() == io::ErrorKind::UnexpectedEof {
+				RequestError::BadRequest("unexpected end of the request")
+			} else {
+				RequestError::Io(e)
+			}
+		})?;
+
+		// Every chunk ends with CRLF
+		let end = read_line(reader, 0, RequestError::BadRequest("missing CRLF after chunk data"))?;
+		if end.is_none() {
+			return Err(RequestError::BadRequest("unexpected end of the request"));
+		}
+	}
+
+	let mut trailers_size: usize = 0;
+	for (name, value) in read_fields(reader, &mut trailers_size)? {
+		// Fields that control the message framing or routing are not allowed in trailers
+		if let Some(name) = name {
+			if name != CONTENT_LENGTH && name != TRANSFER_ENCODING && name != HOST {
+				headers.append(name, value);
+			}
+		}
+	}
+
+	Ok(body)
+}
+
+// Read a complete request (head and body) from a stream.
+// Bodies larger than max_body_size bytes are refused with 413.
+pub fn read_request<S: Read + Write>(reader: &mut BufReader<S>, max_body_size: usize) -> Result<Request<Vec<u8>>, RequestError> {
+	let head = parse_request_head(reader)?;
+	let body_length = get_body_length(&head)?;
+
+	if let BodyLength::Fixed(l) = body_length {
+		if l > max_body_size {
+			return Err(RequestError::PayloadTooLarge);
+		}
+	}
+
+	let (mut parts, _) = head.into_parts();
+
+	// The client waits for an interim response before sending the body
+	let expects_continue = parts.version == Version::HTTP_11 && parts.headers
+		.get(EXPECT)
+		.is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+
+	if expects_continue && !matches!(body_length, BodyLength::None) {
+		reader.get_mut()
+			.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+			.and_then(|_| reader.get_mut().flush())
+			.map_err(RequestError::Io)?;
+	}
+
+	let body: Vec<u8> = match body_length {
+		BodyLength::None => vec![],
+		BodyLength::Fixed(l) => {
+			let mut body = vec![0; l];
+
+			reader.read_exact(&mut body).map_err(|e| {
+				if e.kind() == io::ErrorKind::UnexpectedEof {
+					RequestError::BadRequest("unexpected end of the request")
+				} else {
+					RequestError::Io(e)
+				}
+			})?;
+
+			body
+		},
+		BodyLength::Chunked => read_chunked_body(reader, &mut parts.headers, max_body_size)?,
+	};
+
+	Ok(Request::from_parts(parts, body))
+}
+
+// Whether the client wants to keep the connection open after the response (RFC 9112 section 9.3)
+pub fn wants_keep_alive<B>(request: &Request<B>) -> bool {
+	let has_option = |option: &[u8]| {
+		request.headers()
+			.get_all(CONNECTION)
+			.iter()
+			.flat_map(|v| v.as_bytes().split(|c| *c == b','))
+			.any(|o| trim_ows(o).eq_ignore_ascii_case(option))
+	};
+
+	match request.version() {
+		Version::HTTP_11 => !has_option(b"close"),
+		Version::HTTP_10 => has_option(b"keep-alive") && !has_option(b"close"),
+		_ => false
+	}
+}
+
+// Format a date as IMF-fixdate (RFC 9110 section 5.6.7), e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn format_http_date(time: SystemTime) -> String {
+	DateTime::<Utc>::from(time)
+		.format("%a, %d %b %Y %H:%M:%S GMT")
+		.to_string()
+}
+
+// Parse a date in any of the formats allowed by RFC 9110 section 5.6.7: IMF-fixdate, the obsolete
+// RFC 850 format and the asctime() format
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+	if let Ok(d) = DateTime::parse_from_rfc2822(date) {
+		return Some(d.into());
+	}
+
+	["%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"].iter()
+		.find_map(|f| NaiveDateTime::parse_from_str(date, f).ok())
+		.map(|d| Utc.from_utc_datetime(&d).into())
+}
+
+// Parse a non-negative integer made of digits only
+fn parse_digits(s: &str) -> Option<u64> {
+	if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+		return None;
+	}
+
+	s.parse().ok()
+}
 
-use crate::printing::{print_msg, MsgType};
+// Parse a Range header (RFC 9110 section 14.2) for a content of len bytes.
+// Returns None if the header is invalid or uses a unit other than bytes, in which case it must be
+// ignored. Otherwise returns the satisfiable ranges as inclusive (first, last) byte positions, sorted
+// and with overlapping ranges merged. An empty list means that no range can be satisfied.
+pub fn parse_range(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+	let (unit, range_set) = header.split_once('=')?;
 
-// Split a header line to get keys and values out of it
-fn split_keyval(header_line: &str) -> Result<(&str, &str), ()> {
-	let keyval: Vec<&str> = header_line.split(": ").collect();
+	if !unit.trim().eq_ignore_ascii_case("bytes") {
+		return None;
+	}
+
+	let specs: Vec<&str> = range_set.split(',')
+		.map(|r| r.trim())
+		.filter(|r| !r.is_empty())
+		.collect();
+
+	if specs.is_empty() || specs.len() > MAX_RANGES {
+		return None;
+	}
+
+	let mut ranges: Vec<(u64, u64)> = vec![];
+
+	for spec in specs {
+		let (first, last) = spec.split_once('-')?;
+
+		if first.is_empty() {
+			// Suffix range: the last N bytes
+			let suffix = parse_digits(last)?;
+
+			if suffix > 0 && len > 0 {
+				ranges.push((len.saturating_sub(suffix), len - 1));
+			}
+		} else {
+			let first = parse_digits(first)?;
+			let last = if last.is_empty() { None } else { Some(parse_digits(last)?) };
+
+			if last.is_some_and(|l| l < first) {
+				return None;
+			}
+
+			if first < len {
+				ranges.push((first, last.map_or(len - 1, |l| l.min(len - 1))));
+			}
+		}
+	}
+
+	ranges.sort_unstable();
+
+	// Merge overlapping and adjacent ranges
+	let mut merged: Vec<(u64, u64)> = vec![];
+	for (first, last) in ranges {
+		match merged.last_mut() {
+			Some(prev) if first <= prev.1 + 1 => prev.1 = prev.1.max(last),
+			_ => merged.push((first, last))
+		}
+	}
+
+	Some(merged)
+}
+
+// Decode the percent-encoded octets of a URI path (RFC 3986 section 2.1).
+// Returns None if an escape is malformed, if the result is not valid UTF-8 or if it contains NUL.
+pub fn percent_decode(path: &str) -> Option<String> {
+	let bytes = path.as_bytes();
+	let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+
+	while i < bytes.len() {
+		if bytes[i] == b'%' {
+			let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+
+			if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+				return None;
+			}
+
+			decoded.push(u8::from_str_radix(hex, 16).ok()?);
+			i += 3;
+		} else {
+			decoded.push(bytes[i]);
+			i += 1;
+		}
+	}
+
+	if decoded.contains(&0) {
+		return None;
+	}
+
+	String::from_utf8(decoded).ok()
+}
+
+// Percent-encode a path, every octet is encoded except for the unreserved characters (RFC 3986
+// section 2.3) and '/'
+pub fn percent_encode(path: &str) -> String {
+	let mut encoded = String::with_capacity(path.len());
+
+	for b in path.bytes() {
+		if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+			encoded.push(b as char);
+		} else {
+			encoded.push_str(&format!("%{:02X}", b));
+		}
+	}
+
+	encoded
+}
+
+// Serialize the response head: status line and header fields, each one terminated by CRLF
+fn serialize_head(parts: &response::Parts) -> Vec<u8> {
+	let mut head: Vec<u8> = vec![];
 
-	// The line couldn't be splitted
-	if keyval.len() == 1 || keyval.len() > 2 { return Err(()) }
+	// The server always answers with its highest version, HTTP/1.0 clients included (RFC 9110 section 2.5)
+	head.extend_from_slice(format!(
+		"HTTP/1.1 {} {}\r\n",
+		parts.status.as_str(),
+		parts.status.canonical_reason().unwrap_or("")
+	).as_bytes());
 
-	Ok((keyval[0], keyval[1]))
+	// Header values are written as they are, without assuming any encoding
+	for (key, value) in parts.headers.iter() {
+		head.extend_from_slice(key.as_str().as_bytes());
+		head.extend_from_slice(b": ");
+		head.extend_from_slice(value.as_bytes());
+		head.extend_from_slice(b"\r\n");
+	}
+
+	head.extend_from_slice(b"\r\n");
+
+	head
+}
+
+// Streams that responses are written to
+pub trait ResponseWriter: Write {
+	// The underlying TCP stream, if data can be written to it directly (no TLS)
+	fn as_tcp_stream(&self) -> Option<&TcpStream> {
+		None
+	}
 }
 
-// Parse the text got from http into a Request
-pub fn parse_request(request: &str) -> Result<Request<String>, ()> {
+impl ResponseWriter for TcpStream {
+	fn as_tcp_stream(&self) -> Option<&TcpStream> {
+		Some(self)
+	}
+}
 
-	// Divide the string into lines
-	let mut splitted_request: Vec<&str> = request.lines().collect();
+impl<S: Session, T: Read + Write> ResponseWriter for StreamOwned<S, T> {}
 
-	// Empty request
-	if splitted_request.len() == 0 {
-		return Err(());
+impl<W: ResponseWriter + ?Sized> ResponseWriter for &mut W {
+	fn as_tcp_stream(&self) -> Option<&TcpStream> {
+		(**self).as_tcp_stream()
 	}
+}
+
+// Copy a part of a file to a socket through the sendfile system call, the data doesn't pass through
+// user space. Returns Ok(false) if sendfile cannot be used and nothing has been sent.
+#[cfg(target_os = "linux")]
+fn sendfile_all(socket: &TcpStream, file: &std::fs::File, mut offset: u64, mut len: u64) -> io::Result<bool> {
+	use std::os::unix::io::AsRawFd;
+
+	let mut first_call = true;
+
+	while len > 0 {
+		let mut off = offset as libc::off_t;
 
-	// Divide the first line by spaces, it contains several info
-	let first_line: Vec<&str> = splitted_request[0].split(' ').collect();
-	
-	// Remove the first item, which is memorized by first_line
-	splitted_request.remove(0);
+		// Linux transfers at most 0x7ffff000 bytes per call
+		let count = len.min(0x7fff_f000) as usize;
+		let sent = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut off, count) };
 
-	// Build the request as libhttp::request::Builder
-	let mut builder: Builder = Builder::new()
-		.method(first_line[0])
-		.uri(first_line[1]);
-	
-	// Split lines to get keys and values
-	// The first invalid key-value pair will be interpreted as first
-	// line of the request body
-	while let Ok((key, val)) = split_keyval(splitted_request[0]) {
-		// Update the request builder with the request header
-		builder = builder.header(key, val);
+		if sent < 0 {
+			let error = io::Error::last_os_error();
 
-		// Remove the header line
-		splitted_request.remove(0);
+			match error.raw_os_error() {
+				Some(libc::EINTR) => continue,
+				Some(libc::EINVAL) | Some(libc::ENOSYS) if first_call => return Ok(false),
+				_ => return Err(error)
+			}
+		}
+
+		// The file got truncated while sending it
+		if sent == 0 {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shorter than expected"));
+		}
+
+		first_call = false;
+		offset += sent as u64;
+		len -= sent as u64;
 	}
 
-	let body_content = splitted_request.join("\n");
-	Ok(builder.body(body_content).unwrap())
+	Ok(true)
 }
 
-pub fn send_response(mut stream: TcpStream, response: Response<Vec<u8>>) {
-	// Get the response as string
-	let (response_header, mut response_body) = response.into_parts();
+// Write a body of unknown length with chunked transfer coding, returns the size of the body
+fn write_chunked<W: Write>(stream: &mut W, mut reader: Box<dyn Read + Send>) -> io::Result<u64> {
+	let mut buffer: Vec<u8> = vec![0; 16 * 1024];
+	let mut body_size: u64 = 0;
+
+	loop {
+		let read_bytes = match reader.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(n) => n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e)
+		};
+
+		stream.write_all(format!("{:x}\r\n", read_bytes).as_bytes())?;
+		stream.write_all(&buffer[..read_bytes])?;
+		stream.write_all(b"\r\n")?;
+
+		body_size += read_bytes as u64;
+	}
+
+	// Last chunk, no trailers
+	stream.write_all(b"0\r\n\r\n")?;
+
+	Ok(body_size)
+}
+
+// Write the response head and body, returns the size of the body (without chunked coding).
+// Bodies of unknown length are sent with chunked transfer coding if the Transfer-Encoding header is
+// set, otherwise they are delimited by the end of the connection.
+fn write_response<W: ResponseWriter>(stream: &mut W, head: Vec<u8>, body: Body, chunked: bool) -> io::Result<u64> {
+	let body_size = match body {
+		// Small bodies are sent along with the head
+		Body::Bytes(b) => {
+			let mut final_response = head;
+			final_response.extend_from_slice(&b);
+
+			stream.write_all(&final_response)?;
+			b.len() as u64
+		},
+		#[cfg(target_os = "linux")]
+		Body::File { file, offset, len } if stream.as_tcp_stream().is_some() => {
+			stream.write_all(&head)?;
+			stream.flush()?;
+
+			if !sendfile_all(stream.as_tcp_stream().unwrap(), &file, offset, len)? {
+				io::copy(&mut Body::File { file, offset, len }.into_reader(), stream)?;
+			}
+
+			len
+		},
+		body => {
+			stream.write_all(&head)?;
+
+			if chunked {
+				write_chunked(stream, body.into_reader())?
+			} else {
+				io::copy(&mut body.into_reader(), stream)?
+			}
+		}
+	};
+
+	stream.flush()?;
+
+	Ok(body_size)
+}
+
+// Send a response, returns the number of body bytes sent
+pub fn send_response<W: ResponseWriter>(stream: &mut W, response: Response<Body>) -> Result<u64, ()> {
+	let (mut parts, body) = response.into_parts();
+
+	// Mandatory headers
+	if !parts.headers.contains_key(DATE) {
+		let date = HeaderValue::from_str(&format_http_date(SystemTime::now())).unwrap();
+		parts.headers.insert(DATE, date);
+	}
+
+	if !parts.headers.contains_key(SERVER) {
+		parts.headers.insert(SERVER, HeaderValue::from_static(SERVER_NAME));
+	}
+
+	let chunked = parts.headers
+		.get(TRANSFER_ENCODING)
+		.is_some_and(|t| t.as_bytes().eq_ignore_ascii_case(b"chunked"));
+
+	// Send the whole response
+	write_response(stream, serialize_head(&parts), body, chunked).map_err(|e| {
+		error!("Failed to send response: {}", e);
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Cursor;
+
+	fn read(raw: &[u8], max_body_size: usize) -> Result<Request<Vec<u8>>, RequestError> {
+		let mut reader = BufReader::new(Cursor::new(raw.to_vec()));
+		read_request(&mut reader, max_body_size)
+	}
+
+	fn status_of(raw: &[u8]) -> Option<u16> {
+		read(raw, 1024).err().and_then(|e| e.status())
+	}
+
+	#[test]
+	fn request_head() {
+		let request = read(b"\r\nGET /a?b=c HTTP/1.1\r\nHost: example.com\r\nX-Test:  value \r\n\r\n", 1024).unwrap();
+
+		assert_eq!(request.method(), Method::GET);
+		assert_eq!(request.uri(), "/a?b=c");
+		assert_eq!(request.version(), Version::HTTP_11);
+		assert_eq!(request.headers().get("x-test").unwrap(), "value");
+		assert!(request.body().is_empty());
+	}
+
+	#[test]
+	fn closed_before_request() {
+		assert!(matches!(read(b"", 1024), Err(RequestError::Closed)));
+	}
+
+	#[test]
+	fn bad_request_line() {
+		assert_eq!(status_of(b"GET /\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET / HTTP/1.1 extra\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET / HTTP/1.1\rX\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\r\n"), Some(400));
+	}
+
+	#[test]
+	fn host_header() {
+		assert_eq!(status_of(b"GET / HTTP/1.1\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"), Some(400));
+
+		// Not required in HTTP/1.0
+		assert!(read(b"GET / HTTP/1.0\r\n\r\n", 1024).is_ok());
+	}
+
+	#[test]
+	fn invalid_header_fields() {
+		assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\r\nNo colon\r\n\r\n"), Some(400));
+	}
+
+	#[test]
+	fn obs_fold() {
+		let request = read(b"GET / HTTP/1.1\r\nHost: a\r\nX-Test: first\r\n \t second\r\n\r\n", 1024).unwrap();
+		assert_eq!(request.headers().get("x-test").unwrap(), "first second");
+
+		// A continuation line needs a field to continue
+		assert_eq!(status_of(b"GET / HTTP/1.1\r\n folded\r\nHost: a\r\n\r\n"), Some(400));
+	}
+
+	#[test]
+	fn request_line_too_long() {
+		let mut raw = b"GET /".to_vec();
+		raw.extend(vec![b'a'; MAX_REQUEST_LINE_LEN]);
+		raw.extend_from_slice(b" HTTP/1.1\r\nHost: a\r\n\r\n");
+
+		assert_eq!(status_of(&raw), Some(414));
+	}
+
+	#[test]
+	fn headers_too_large() {
+		// A single line that is too long
+		let mut raw = b"GET / HTTP/1.1\r\nHost: a\r\nX-Test: ".to_vec();
+		raw.extend(vec![b'a'; MAX_HEADER_LINE_LEN]);
+		raw.extend_from_slice(b"\r\n\r\n");
+		assert_eq!(status_of(&raw), Some(431));
+
+		// Too many fields
+		let mut raw = b"GET / HTTP/1.1\r\nHost: a\r\n".to_vec();
+		for i in 0..MAX_HEADERS_COUNT {
+			raw.extend_from_slice(format!("X-{}: a\r\n", i).as_bytes());
+		}
+		raw.extend_from_slice(b"\r\n");
+		assert_eq!(status_of(&raw), Some(431));
+
+		// Too many bytes in total
+		let mut raw = b"GET / HTTP/1.1\r\nHost: a\r\n".to_vec();
+		for i in 0..(MAX_HEADERS_SIZE / 4096 + 1) {
+			raw.extend_from_slice(format!("X-{}: {}\r\n", i, "a".repeat(4096)).as_bytes());
+		}
+		raw.extend_from_slice(b"\r\n");
+		assert_eq!(status_of(&raw), Some(431));
+	}
+
+	#[test]
+	fn http_version() {
+		assert_eq!(status_of(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n"), Some(505));
+		assert_eq!(status_of(b"GET / HTTP/1\r\nHost: a\r\n\r\n"), Some(400));
+		assert_eq!(status_of(b"GET / http/1.1\r\nHost: a\r\n\r\n"), Some(400));
+	}
+
+	#[test]
+	fn ranges() {
+		assert_eq!(parse_range("bytes=0-99", 1000), Some(vec![(0, 99)]));
+		// Suffix range: the last bytes, the whole content if it is shorter
+		assert_eq!(parse_range("bytes=-100", 1000), Some(vec![(900, 999)]));
+		assert_eq!(parse_range("bytes=-2000", 1000), Some(vec![(0, 999)]));
+		// Open-ended range and last position past the end
+		assert_eq!(parse_range("bytes=900-", 1000), Some(vec![(900, 999)]));
+		assert_eq!(parse_range("bytes=900-5000", 1000), Some(vec![(900, 999)]));
+		// Overlapping and adjacent ranges are merged
+		assert_eq!(parse_range("bytes=50-99, 0-10, 5-20, 21-30, 200-", 1000), Some(vec![(0, 30), (50, 99), (200, 999)]));
+		assert_eq!(parse_range("BYTES = 0-0", 1000), Some(vec![(0, 0)]));
+	}
+
+	#[test]
+	fn unsatisfiable_ranges() {
+		// Valid headers without any satisfiable range, answered with 416
+		assert_eq!(parse_range("bytes=1000-", 1000), Some(vec![]));
+		assert_eq!(parse_range("bytes=-0", 1000), Some(vec![]));
+		assert_eq!(parse_range("bytes=0-", 0), Some(vec![]));
+	}
+
+	#[test]
+	fn invalid_ranges() {
+		// Ignored, the whole content is sent
+		assert_eq!(parse_range("items=0-10", 1000), None);
+		assert_eq!(parse_range("bytes=10-5", 1000), None);
+		assert_eq!(parse_range("bytes=a-5", 1000), None);
+		assert_eq!(parse_range("bytes=+1-5", 1000), None);
+		assert_eq!(parse_range("bytes=5", 1000), None);
+		assert_eq!(parse_range("bytes=", 1000), None);
+	}
+
+	#[test]
+	fn too_many_ranges() {
+		let list = |n: u64| (0..n).map(|i| format!("{}-{}", i * 10, i * 10)).collect::<Vec<String>>().join(",");
+
+		assert_eq!(parse_range(&format!("bytes={}", list(MAX_RANGES as u64)), 10000).unwrap().len(), MAX_RANGES);
+		assert_eq!(parse_range(&format!("bytes={}", list(MAX_RANGES as u64 + 1)), 10000), None);
+	}
+
+	#[test]
+	fn percent_decoding() {
+		assert_eq!(percent_decode("/caf%C3%A9/a%20b").as_deref(), Some("/café/a b"));
+		assert_eq!(percent_decode("/%2e%2E/x").as_deref(), Some("/../x"));
+		assert_eq!(percent_decode("/plain").as_deref(), Some("/plain"));
+	}
+
+	#[test]
+	fn invalid_percent_encoding() {
+		// NUL cannot be part of a file name
+		assert_eq!(percent_decode("/a%00.md"), None);
+		// Invalid and truncated escapes
+		assert_eq!(percent_decode("/a%zz"), None);
+		assert_eq!(percent_decode("/a%+1"), None);
+		assert_eq!(percent_decode("/a%4"), None);
+		assert_eq!(percent_decode("/a%"), None);
+		// Not UTF-8
+		assert_eq!(percent_decode("/a%ff"), None);
+	}
+
+	#[test]
+	fn percent_encoding() {
+		assert_eq!(percent_encode("/café/a b?"), "/caf%C3%A9/a%20b%3F");
+	}
+
+	#[test]
+	fn content_length_body() {
+		let request = read(b"POST /form HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello", 1024).unwrap();
+
+		assert_eq!(request.method(), Method::POST);
+		assert_eq!(request.body(), b"hello");
+	}
+
+	#[test]
+	fn content_length_too_large() {
+		let result = read(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhello world", 10);
+
+		assert!(matches!(result, Err(RequestError::PayloadTooLarge)));
+	}
+
+	#[test]
+	fn chunked_body_with_trailers() {
+		let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+			5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\nHost: b\r\n\r\n";
+		let request = read(raw, 1024).unwrap();
+
+		assert_eq!(request.body(), b"hello world");
+		assert_eq!(request.headers().get("checksum").unwrap(), "abc");
+
+		// Framing and routing fields in the trailers are dropped
+		assert_eq!(request.headers().get_all(HOST).iter().count(), 1);
+	}
 
-	// Text that will be sent, as bytes
-	let mut final_response: Vec<u8> = vec![];
+	#[test]
+	fn chunked_body_too_large() {
+		let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+			6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n";
 
-	// Insert version and status code
-	{
-		let version_str = format!("{:?}", response_header.version);
-		let status_code_str = response_header.status.as_str();
-		final_response.append(&mut format!("{} {}", version_str, status_code_str).as_bytes().to_owned());
+		assert!(matches!(read(raw, 10), Err(RequestError::PayloadTooLarge)));
 	}
 
-	final_response.push(b'\n');
+	#[test]
+	fn chunked_size_overflow() {
+		let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+			1\r\na\r\nffffffffffffffff\r\n";
 
-	// Insert header fields
-	for (key, value) in response_header.headers.iter() {
-		final_response.append(&mut format!("{}: {}", key, value.to_str().unwrap()).as_bytes().to_owned());
-		final_response.push(b'\n');
+		assert!(matches!(read(raw, 1024), Err(RequestError::PayloadTooLarge)));
 	}
-	
-	final_response.push(b'\n');
 
-	// Insert body
-	final_response.append(&mut response_body);
+	#[test]
+	fn chunked_size_out_of_range() {
+		let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+			10000000000000000\r\n";
 
-	// Send the response
-	if let Err(_) = stream.write(final_response.as_slice()) {
-		print_msg("Failed to send response", MsgType::Error);
+		assert!(matches!(read(raw, 1024), Err(RequestError::PayloadTooLarge)));
 	}
 }
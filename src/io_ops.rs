@@ -1,24 +1,282 @@
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, ErrorKind};
+use std::path::{Component, Path, PathBuf};
 
-use crate::config::{self, CONFIG};
-use crate::printing::{print_msg, MsgType};
+use crate::config::{SymlinkPolicy, CONFIG};
 
-// Check if a file can be sent by the webserver by checking if it's inside WWW
-fn is_file_accessible<P: AsRef<Path>>(path: P) -> bool {
-	let root_dir = Path::new(&CONFIG.server.www_path).canonicalize().unwrap();
+// Maximum number of symbolic links followed while resolving a path
+const MAX_SYMLINKS: usize = 40;
 
-	if path.as_ref().canonicalize().unwrap().starts_with(&root_dir) {
-		return true;
+// Errors that may occur while accessing a file in WWW
+#[derive(Debug)]
+pub enum FileError {
+	NotFound,
+	// The file exists but it must not be sent (hidden file, symbolic link, etc...)
+	Forbidden(&'static str),
+	Io(io::Error),
+}
+
+impl FileError {
+	// Status code of the response
+	pub fn status(&self) -> u16 {
+		match self {
+			FileError::NotFound => 404,
+			FileError::Forbidden(_) => 403,
+			FileError::Io(_) => 500,
+		}
+	}
+}
+
+impl fmt::Display for FileError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			FileError::NotFound => write!(f, "not found"),
+			FileError::Forbidden(reason) => write!(f, "access denied ({})", reason),
+			FileError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl From<io::Error> for FileError {
+	fn from(e: io::Error) -> Self {
+		match e.kind() {
+			// "file.txt/other" and names that cannot exist (e.g. containing NUL)
+			ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::InvalidInput => FileError::NotFound,
+			ErrorKind::PermissionDenied => FileError::Forbidden("permission denied"),
+			_ => FileError::Io(e),
+		}
+	}
+}
+
+// Entry of a directory in WWW
+pub struct DirEntry {
+	pub name: OsString,
+	pub metadata: fs::Metadata,
+}
+
+// An opened file or directory.
+// On Unix the path is resolved through openat, one component at a time, so that it cannot be changed
+// (e.g. by swapping in a symbolic link) between the checks and the use of the file.
+#[cfg(unix)]
+struct Handle {
+	file: File,
+}
+
+#[cfg(unix)]
+impl Handle {
+	fn open_root(path: &str) -> io::Result<Handle> {
+		Ok(Handle { file: File::open(path)? })
+	}
+
+	// Open an entry of this directory. Returns None if the entry is a symbolic link and follow is false.
+	fn open_child(&self, name: &OsStr, follow: bool) -> io::Result<Option<Handle>> {
+		use std::ffi::CString;
+		use std::os::unix::ffi::OsStrExt;
+		use std::os::unix::io::{AsRawFd, FromRawFd};
+
+		let c_name = CString::new(name.as_bytes()).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+
+		// O_NONBLOCK prevents blocking on FIFOs, it has no effect on regular files and directories
+		let mut flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NOCTTY | libc::O_NONBLOCK;
+		if !follow {
+			flags |= libc::O_NOFOLLOW;
+		}
+
+		let fd = unsafe { libc::openat(self.file.as_raw_fd(), c_name.as_ptr(), flags) };
+
+		if fd < 0 {
+			let err = io::Error::last_os_error();
+
+			// O_NOFOLLOW makes openat fail with ELOOP (EMLINK on FreeBSD) on symbolic links
+			if !follow && matches!(err.raw_os_error(), Some(libc::ELOOP) | Some(libc::EMLINK)) {
+				return Ok(None);
+			}
+
+			return Err(err);
+		}
+
+		Ok(Some(Handle { file: unsafe { File::from_raw_fd(fd) } }))
+	}
+
+	fn metadata(&self) -> io::Result<fs::Metadata> {
+		self.file.metadata()
+	}
+
+	fn into_file(self) -> io::Result<File> {
+		Ok(self.file)
+	}
+
+	// Names of the entries of this directory, "." and ".." excluded
+	fn child_names(&self) -> io::Result<Vec<OsString>> {
+		use std::ffi::CStr;
+		use std::os::unix::ffi::OsStrExt;
+		use std::os::unix::io::AsRawFd;
+
+		// The duplicated descriptor is closed by closedir
+		let fd = unsafe { libc::dup(self.file.as_raw_fd()) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		let dir = unsafe { libc::fdopendir(fd) };
+		if dir.is_null() {
+			let err = io::Error::last_os_error();
+			unsafe { libc::close(fd) };
+			return Err(err);
+		}
+
+		let mut names: Vec<OsString> = vec![];
+
+		loop {
+			let entry = unsafe { libc::readdir(dir) };
+			if entry.is_null() {
+				break;
+			}
+
+			let name = OsStr::from_bytes(unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes());
+			if name != "." && name != ".." {
+				names.push(name.to_owned());
+			}
+		}
+
+		unsafe { libc::closedir(dir) };
+
+		Ok(names)
+	}
+}
+
+// On other platforms the path is checked one component at a time, but it may change before the
+// file is opened
+#[cfg(not(unix))]
+struct Handle {
+	path: PathBuf,
+}
+
+#[cfg(not(unix))]
+impl Handle {
+	fn open_root(path: &str) -> io::Result<Handle> {
+		fs::metadata(path)?;
+		Ok(Handle { path: PathBuf::from(path) })
 	}
 
-	false
+	fn open_child(&self, name: &OsStr, follow: bool) -> io::Result<Option<Handle>> {
+		let path = self.path.join(name);
+
+		if !follow && fs::symlink_metadata(&path)?.file_type().is_symlink() {
+			return Ok(None);
+		}
+
+		fs::metadata(&path)?;
+		Ok(Some(Handle { path }))
+	}
+
+	fn metadata(&self) -> io::Result<fs::Metadata> {
+		fs::metadata(&self.path)
+	}
+
+	fn into_file(self) -> io::Result<File> {
+		File::open(&self.path)
+	}
+
+	fn child_names(&self) -> io::Result<Vec<OsString>> {
+		fs::read_dir(&self.path)?
+			.map(|e| e.map(|e| e.file_name()))
+			.collect()
+	}
+}
+
+// Split a path relative to WWW into names, rejecting the ones that must not be accessed
+fn split_path(path: &Path) -> Result<VecDeque<OsString>, FileError> {
+	let mut names: VecDeque<OsString> = VecDeque::new();
+
+	for component in path.components() {
+		match component {
+			Component::Normal(name) => {
+				if !CONFIG.get().server.hidden_files && name.to_string_lossy().starts_with('.') {
+					return Err(FileError::Forbidden("hidden file"));
+				}
+
+				names.push_back(name.to_owned());
+			},
+			Component::ParentDir => return Err(FileError::Forbidden("parent directory in path")),
+			// Paths are relative to WWW even if they start with '/'
+			Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+		}
+	}
+
+	Ok(names)
+}
+
+// Open a file or directory which is located in the WWW directory, symbolic links are followed
+// according to the configured policy
+fn resolve(path: &Path) -> Result<Handle, FileError> {
+	let policy = CONFIG.get().server.symlinks;
+	let mut names = split_path(path)?;
+
+	let mut current = Handle::open_root(&CONFIG.get().server.www_path)?;
+	// Path of current (relative to WWW), it contains no symbolic links
+	let mut current_path = PathBuf::new();
+	let mut followed_links: usize = 0;
+
+	while let Some(name) = names.pop_front() {
+		if let Some(child) = current.open_child(&name, policy == SymlinkPolicy::Follow)? {
+			current = child;
+			current_path.push(name);
+			continue;
+		}
+
+		// Symbolic link
+		if policy == SymlinkPolicy::Deny {
+			return Err(FileError::Forbidden("symbolic link"));
+		}
+
+		followed_links += 1;
+		if followed_links > MAX_SYMLINKS {
+			return Err(FileError::Forbidden("too many symbolic links"));
+		}
+
+		// Find out where the link points to, then start again from WWW along that path without
+		// following links. If a link is swapped in meanwhile, it is found and checked again.
+		let root_dir = fs::canonicalize(&CONFIG.get().server.www_path)?;
+		let target = fs::canonicalize(root_dir.join(&current_path).join(&name))?;
+		let target = target.strip_prefix(&root_dir)
+			.map_err(|_| FileError::Forbidden("symbolic link pointing outside the WWW directory"))?;
+
+		let mut target_names = split_path(target)?;
+		target_names.append(&mut names);
+		names = target_names;
+
+		current = Handle::open_root(&CONFIG.get().server.www_path)?;
+		current_path.clear();
+	}
+
+	Ok(current)
+}
+
+// Resolve a path, logging why the file cannot be accessed
+fn get_checked_handle<P: AsRef<Path>>(path: P) -> Result<Handle, FileError> {
+	let path = path.as_ref();
+
+	debug!("Getting {} from disk...", path.display());
+
+	resolve(path).map_err(|e| {
+		match &e {
+			FileError::NotFound => debug!("File {} not found.", path.display()),
+			FileError::Forbidden(_) => warn!("File {} cannot be accessed: {}.", path.display(), e),
+			FileError::Io(_) => error!("Error while opening the file {}: {}", path.display(), e),
+		}
+
+		e
+	})
 }
 
 // Returns the config file's content (config.toml)
 // This is the only function that can return a file placed outside WWW
-pub fn get_config_file() -> Result<String, ()> {
-	let file_content = fs::read_to_string(Path::new(config::DEFAULT_CONFIG_PATH));
+pub fn get_config_file(path: &str) -> Result<String, ()> {
+	let file_content = fs::read_to_string(Path::new(path));
 
 	if let Ok(c) = file_content {
 		return Ok(c);
@@ -28,32 +286,51 @@ pub fn get_config_file() -> Result<String, ()> {
 }
 
 // Get the content of a file which is located in the WWW directory (see defaults.rs module)
-pub fn get_file_content<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, ()> {
-
-	// Path that includes WWW (but technically still a relative path)
-	let mut complete_path: PathBuf = PathBuf::from(&CONFIG.server.www_path);
-	complete_path.push(&path);
+pub fn get_file_content<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, FileError> {
 
-	print_msg(format!("Getting {} from disk...", complete_path.to_str().unwrap()), MsgType::Info);
+	let (mut file, len) = open_file(&path)?;
+	let mut content: Vec<u8> = Vec::with_capacity(len as usize);
 
-	// Check if the file exists
-	if !complete_path.exists() {
-		print_msg(format!("File {} not found.", path.as_ref().to_str().unwrap()), MsgType::Warning);
-		return Err(());
+	if let Err(e) = file.read_to_end(&mut content) {
+		error!("Error while getting the file {}: {}", path.as_ref().display(), e);
+		return Err(FileError::Io(e));
 	}
 
-	// Check whether the requested file is contained in WWW
-	if !is_file_accessible(&complete_path) {
-		print_msg(format!("File {} cannot be accessed because it resides outside the WWW directory.", path.as_ref().to_str().unwrap()), MsgType::Warning);
-		return Err(());
-	}
+	Ok(content)
+}
+
+// Open a file which is located in the WWW directory without reading it, returns the file and its size
+pub fn open_file<P: AsRef<Path>>(path: P) -> Result<(File, u64), FileError> {
 
-	let content = fs::read(&complete_path);
+	let handle = get_checked_handle(path)?;
+	let metadata = handle.metadata()?;
 
-	if let Err(_) = content {
-		print_msg(format!("Error while getting the file {}", complete_path.to_str().unwrap()), MsgType::Error);
-		return Err(());
+	// Directories can be opened too, but they cannot be sent
+	if !metadata.is_file() {
+		return Err(FileError::NotFound);
 	}
 
-	Ok(content.unwrap())
+	Ok((handle.into_file()?, metadata.len()))
+}
+
+// Get the metadata (size, modification time, etc...) of a file which is located in the WWW directory
+pub fn get_file_metadata<P: AsRef<Path>>(path: P) -> Result<fs::Metadata, FileError> {
+	Ok(resolve(path.as_ref())?.metadata()?)
+}
+
+// Get the entries of a directory which is located in the WWW directory.
+// Entries that cannot be accessed are left out.
+pub fn read_dir<P: AsRef<Path>>(path: P) -> Result<Vec<DirEntry>, FileError> {
+
+	let handle = get_checked_handle(&path)?;
+	let names = handle.child_names()?;
+
+	let entries = names.into_iter()
+		.filter_map(|name| {
+			let metadata = get_file_metadata(path.as_ref().join(&name)).ok()?;
+			Some(DirEntry { name, metadata })
+		})
+		.collect();
+
+	Ok(entries)
 }
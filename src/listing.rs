@@ -0,0 +1,120 @@
+// Pages listing the content of the directories that have no index page (only for the directories
+// enabled in config.toml).
+
+use chrono::{DateTime, Local};
+
+use std::cmp::Ordering;
+use std::path::{Component, Path};
+use std::time::SystemTime;
+
+use crate::config::CONFIG;
+use crate::html::build_site_page;
+use crate::http::percent_encode;
+use crate::io_ops::{read_dir, FileError};
+
+struct Entry {
+	name: String,
+	is_dir: bool,
+	len: u64,
+	modified: Option<SystemTime>,
+}
+
+// Whether the content of a directory can be listed.
+// dir_path does not include WWW (e.g. "docs/files/")
+pub fn is_listing_enabled(dir_path: &str) -> bool {
+	let dir = Path::new(dir_path);
+
+	// "docs/../private/" would otherwise be considered part of "docs/"
+	if dir.components().any(|c| c == Component::ParentDir) {
+		return false;
+	}
+
+	CONFIG.get().listing.directories
+		.iter()
+		.any(|d| dir.starts_with(d.trim_start_matches('/')))
+}
+
+// Generate the HTML page listing the content of a directory.
+// dir_uri is the requested path, including the leading and trailing slashes (e.g. "/docs/files/")
+pub fn render_listing(dir_uri: &str) -> Result<String, FileError> {
+	let dir_path = dir_uri.strip_prefix('/').unwrap_or(dir_uri);
+
+	debug!("Generating the listing of directory {}...", dir_uri);
+
+	let mut entries: Vec<Entry> = read_dir(dir_path)?
+		.into_iter()
+		.map(|e| Entry {
+			name: e.name.to_string_lossy().into_owned(),
+			is_dir: e.metadata.is_dir(),
+			len: e.metadata.len(),
+			modified: e.metadata.modified().ok()
+		})
+		.collect();
+
+	// Directories first, then files, both in alphabetical order
+	entries.sort_by(|a, b| match (a.is_dir, b.is_dir) {
+		(true, false) => Ordering::Less,
+		(false, true) => Ordering::Greater,
+		_ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+	});
+
+	let heading = format!("Index of {}", dir_uri);
+	let mut md_fc = format!("# {}\n\n| Name | Size | Last modified |\n| :--- | ---: | :--- |\n", escape_md(&heading));
+
+	if dir_uri != "/" {
+		md_fc.push_str("| [../](../) | | |\n");
+	}
+
+	for entry in entries {
+		let (name, href, size) = {
+			if entry.is_dir {
+				(format!("{}/", entry.name), format!("{}/", percent_encode(&entry.name)), String::new())
+			} else {
+				// Markdown files are linked to the page they are rendered into
+				let page = entry.name.strip_suffix(".md").unwrap_or(&entry.name);
+				(entry.name.clone(), percent_encode(page), format_size(entry.len))
+			}
+		};
+
+		let modified = entry.modified
+			.map(|m| DateTime::<Local>::from(m).format("%Y-%m-%d %H:%M").to_string())
+			.unwrap_or_default();
+
+		md_fc.push_str(&format!("| [{}]({}) | {} | {} |\n", escape_md(&name), href, size, modified));
+	}
+
+	Ok(build_site_page(&md_fc, &heading))
+}
+
+// Escape the characters that have a meaning in Markdown (table separators included)
+fn escape_md(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		if c.is_ascii_punctuation() {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+
+	escaped
+}
+
+// Human-readable file size (e.g. "1.5 KiB")
+fn format_size(len: u64) -> String {
+	const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+	if len < 1024 {
+		return format!("{} B", len);
+	}
+
+	let mut size = len as f64 / 1024.0;
+	let mut unit = 0;
+
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	format!("{:.1} {}", size, UNITS[unit])
+}
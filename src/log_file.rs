@@ -0,0 +1,244 @@
+// Log files shared by the server messages and the access log: lines are appended to the file, which
+// is rotated when it gets too large or when a new hour/day starts, and can be reopened after it has
+// been moved by an external tool (e.g. logrotate).
+
+use chrono::{DateTime, Local};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Log, Rotation};
+
+pub struct LogFile {
+	path: PathBuf,
+	// None if the file could not be reopened, lines are dropped until the next reopen
+	file: Option<File>,
+	// Size of the file, used for size-based rotation
+	size: u64,
+	// Time-based rotation period the content of the file belongs to
+	period: String,
+	rotation: Rotation,
+	max_size: u64,
+	keep: usize,
+}
+
+// Identifier of the rotation period of a time, the file is rotated when it changes
+fn period_of(rotation: Rotation, time: DateTime<Local>) -> String {
+	match rotation {
+		Rotation::Never => String::new(),
+		Rotation::Hourly => time.format("%Y-%m-%d %H").to_string(),
+		Rotation::Daily => time.format("%Y-%m-%d").to_string(),
+	}
+}
+
+fn current_period(rotation: Rotation) -> String {
+	period_of(rotation, Local::now())
+}
+
+// Open a file for appending, returns its size and the rotation period of its last modification
+fn open_append(path: &Path, rotation: Rotation) -> io::Result<(File, u64, String)> {
+	let file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)?;
+	let metadata = file.metadata()?;
+	let period = metadata.modified()
+		.map(|m| period_of(rotation, DateTime::<Local>::from(m)))
+		.unwrap_or_else(|_| current_period(rotation));
+
+	Ok((file, metadata.len(), period))
+}
+
+impl LogFile {
+	// Open a log file, it is rotated according to the settings of the [log] section
+	pub fn open(path: &str, settings: &Log) -> io::Result<LogFile> {
+		let path = PathBuf::from(path);
+		let (file, size, period) = open_append(&path, settings.rotation)?;
+
+		Ok(LogFile {
+			path,
+			file: Some(file),
+			size,
+			period,
+			rotation: settings.rotation,
+			max_size: settings.max_size,
+			keep: settings.keep
+		})
+	}
+
+	// Close the file and open it again at the same path, creating it if it has been moved
+	pub fn reopen(&mut self) -> io::Result<()> {
+		self.file = None;
+
+		let (file, size, period) = open_append(&self.path, self.rotation)?;
+		self.file = Some(file);
+		self.size = size;
+		self.period = period;
+
+		Ok(())
+	}
+
+	// Path of the n-th rotated file (e.g. access.log.1)
+	fn rotated_path(&self, n: usize) -> PathBuf {
+		let mut path = self.path.clone().into_os_string();
+		path.push(format!(".{}", n));
+
+		PathBuf::from(path)
+	}
+
+	// Rename the file to <path>.1, shifting the older ones (<path>.1 to <path>.2 and so on). Only
+	// the most recent `keep` rotated files are kept.
+	fn rotate(&mut self) -> io::Result<()> {
+		let keep = self.keep;
+		self.file = None;
+
+		if keep == 0 {
+			fs::remove_file(&self.path)?;
+		} else {
+			// The oldest file is replaced
+			for n in (1..keep).rev() {
+				let from = self.rotated_path(n);
+
+				if from.exists() {
+					fs::rename(&from, self.rotated_path(n + 1))?;
+				}
+			}
+
+			fs::rename(&self.path, self.rotated_path(1))?;
+		}
+
+		self.reopen()
+	}
+
+	fn needs_rotation(&self, line_len: u64) -> bool {
+		let max_size = self.max_size;
+
+		(max_size > 0 && self.size > 0 && self.size + line_len > max_size) || self.period != current_period(self.rotation)
+	}
+
+	// Append a line, rotating the file first if needed.
+	// If the rotation fails, the line is still appended to the current file.
+	pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+		let line_len = line.len() as u64 + 1;
+		let mut rotation_error: Option<io::Error> = None;
+
+		if self.needs_rotation(line_len) {
+			if let Err(e) = self.rotate() {
+				self.reopen()?;
+
+				// Try again in the next period
+				self.period = current_period(self.rotation);
+				rotation_error = Some(e);
+			}
+		}
+
+		let file = match self.file.as_mut() {
+			Some(f) => f,
+			None => return Err(io::Error::new(io::ErrorKind::NotFound, "the log file is not open")),
+		};
+
+		writeln!(file, "{}", line)?;
+		self.size += line_len;
+
+		rotation_error.map_or(Ok(()), Err)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::collections::HashMap;
+	use std::env;
+
+	// Empty directory for the files of a test
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = env::temp_dir().join("webserver-tests-log").join(name);
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		dir
+	}
+
+	fn open(dir: &Path, rotation: Rotation, max_size: u64, keep: usize) -> LogFile {
+		let settings = Log {
+			path: None,
+			rotation,
+			max_size,
+			keep,
+			filters: HashMap::new()
+		};
+
+		LogFile::open(dir.join("test.log").to_str().unwrap(), &settings).unwrap()
+	}
+
+	fn read(path: PathBuf) -> String {
+		fs::read_to_string(path).unwrap()
+	}
+
+	#[test]
+	fn size_rotation() {
+		let dir = test_dir("size");
+		let mut log = open(&dir, Rotation::Never, 10, 3);
+
+		// "line N\n" is 7 bytes, the second line would exceed the limit
+		assert!(!log.needs_rotation(7));
+		log.write_line("line 1").unwrap();
+		assert!(log.needs_rotation(7));
+		log.write_line("line 2").unwrap();
+
+		assert_eq!(read(dir.join("test.log")), "line 2\n");
+		assert_eq!(read(dir.join("test.log.1")), "line 1\n");
+
+		// A line larger than the limit is written to an empty file anyway
+		log.write_line("a very long line").unwrap();
+		log.write_line("line 3").unwrap();
+		assert_eq!(read(dir.join("test.log")), "line 3\n");
+		assert_eq!(read(dir.join("test.log.1")), "a very long line\n");
+		assert_eq!(read(dir.join("test.log.2")), "line 2\n");
+	}
+
+	#[test]
+	fn time_rotation() {
+		let dir = test_dir("time");
+		let mut log = open(&dir, Rotation::Daily, 0, 3);
+
+		log.write_line("today").unwrap();
+		assert!(!log.needs_rotation(1000));
+
+		// As if the first line had been written the day before
+		log.period = period_of(Rotation::Daily, Local::now() - chrono::Duration::days(1));
+		assert!(log.needs_rotation(1));
+		log.write_line("tomorrow").unwrap();
+
+		assert_eq!(read(dir.join("test.log")), "tomorrow\n");
+		assert_eq!(read(dir.join("test.log.1")), "today\n");
+		assert!(!log.needs_rotation(1));
+	}
+
+	#[test]
+	fn keep() {
+		let dir = test_dir("keep");
+		let mut log = open(&dir, Rotation::Never, 1, 2);
+
+		for n in 1..=5 {
+			log.write_line(&n.to_string()).unwrap();
+		}
+
+		// Only the two most recent rotated files are left
+		assert_eq!(read(dir.join("test.log")), "5\n");
+		assert_eq!(read(dir.join("test.log.1")), "4\n");
+		assert_eq!(read(dir.join("test.log.2")), "3\n");
+		assert!(!dir.join("test.log.3").exists());
+
+		// Without rotated files the content is dropped
+		let dir = test_dir("keep_none");
+		let mut log = open(&dir, Rotation::Never, 1, 0);
+		log.write_line("1").unwrap();
+		log.write_line("2").unwrap();
+
+		assert_eq!(read(dir.join("test.log")), "2\n");
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+	}
+}
@@ -1,17 +1,22 @@
 extern crate http as libhttp;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 use chrono::prelude::*;
-use colored::Colorize;
 use threadpool::ThreadPool;
 
-use std::net::{TcpListener, TcpStream};
-use std::io::prelude::*;
-use std::time::Instant;
+use libhttp::Version;
+use libhttp::header::{HeaderValue, CONNECTION, REFERER, TRANSFER_ENCODING, USER_AGENT};
+use rustls::{ServerConfig, ServerSession, Session, StreamOwned};
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::io::{prelude::*, BufReader, ErrorKind};
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 
-use crate::printing::*;
-use crate::config::CONFIG;
+use crate::config::{Command, CONFIG};
+use crate::http::{RequestError, ResponseWriter};
 
 mod http;
 mod css;
@@ -21,25 +26,116 @@ mod router;
 mod html;
 mod config;
 mod printing;
+// Unit tests don't parse the command line
+#[cfg_attr(test, allow(dead_code))]
 mod args;
 mod first_run;
+mod tls;
+mod cache;
+mod build;
+mod body;
+mod compression;
+mod listing;
+mod panics;
+mod access_log;
+mod log_file;
+mod reload;
 
 fn main() {
-	// Create files if they don't exist
+	// Create the files needed to run the server and exit, config.toml may not exist yet
+	if let Command::Init = config::args().command {
+		if let Err(e) = first_run::init(config::args()) {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+
+		return;
+	}
+
+	// Configuration on startup, it may be replaced later by a reload
+	let config = CONFIG.get();
+
+	// Set up the logger before anything is printed
+	printing::init(&config.printing, &config.log);
+
+	// Print the configuration and exit
+	if let Command::PrintConfig = config.command {
+		match toml::to_string(&*config) {
+			// The output can be used as config.toml, so TLS is in a comment
+			Ok(c) => print!("# TLS: {}\n\n{}", if config.server.use_tls { "enabled" } else { "disabled (--no-tls)" }, c),
+			Err(e) => {
+				error!("Cannot print the configuration: {}", e);
+				std::process::exit(1);
+			}
+		}
+
+		return;
+	}
+
+	// Validate the configuration and exit
+	if let Command::CheckConfig = config.command {
+		let problems = config.validate();
+
+		if !problems.is_empty() {
+			eprintln!("{}", config::format_problems(&config.config_path, &problems));
+			std::process::exit(1);
+		}
+
+		println!("The configuration ({}) is valid.", config.config_path);
+		return;
+	}
+
+	// Render the website and exit
+	if let Command::Build { out_path } = &config.command {
+		if build::build_site(out_path).is_err() {
+			std::process::exit(1);
+		}
+
+		return;
+	}
+
+	// Don't start with a configuration that cannot work, a mistyped www_path must not be created
+	let problems = config.validate();
+	if !problems.is_empty() {
+		error!("{}", config::format_problems(&config.config_path, &problems));
+		std::process::exit(1);
+	}
+
+	// Create the files missing from WWW
 	first_run::check_files();
 
 	// Print legend based on the verbosity level
-	print_markers();
+	printing::print_markers();
+
+	printing::print_separator();
+
+	info!("Press Ctrl+C to close the server");
 
-	print_separator();
+	// Panics in request handlers get logged with the request
+	panics::set_hook();
 
-	print_msg("Press Ctrl+C to close the server", MsgType::Info);
+	access_log::init();
 
-	let threads_quantity: usize = CONFIG.server.threads;
-	let address = &CONFIG.server.address;
+	let threads_quantity: usize = config.server.threads;
+	let address = &config.server.address;
+
+	// Load the certificate and the private key before accepting any connection
+	let tls_config: Option<Arc<ServerConfig>> = {
+		if config.server.use_tls {
+			match tls::load_server_config(config.server.cert_path.as_ref(), config.server.key_path.as_ref()) {
+				Ok(c) => Some(c),
+				Err(e) => {
+					error!("{}", e);
+					std::process::exit(1);
+				}
+			}
+		} else {
+			None
+		}
+	};
 
-	let listener = TcpListener::bind(&address)
-		.expect(&format!("Cannot bind {}", address));
+	let mut listener = TcpListener::bind(address)
+		.unwrap_or_else(|_| panic!("Cannot bind {}", address));
 
 	//listener.set_nonblocking(true).unwrap();
 
@@ -48,7 +144,12 @@ fn main() {
 		ThreadPool::new(threads_quantity)
 	));
 
-	print_msg(format!("Thread pool created, total threads: {}", threads_quantity), MsgType::Info);
+	info!("Thread pool created, total threads: {}", threads_quantity);
+
+	#[cfg(unix)]
+	handle_sighup(pool.clone());
+
+	reload::watch_config_file(pool.clone());
 
 	// Set the Ctrl+C handler
 	let pool_clone_ctrlc = pool.clone();
@@ -57,63 +158,284 @@ fn main() {
 
 		// Join threads before shutting down
 		pool_clone_ctrlc.lock().unwrap().join();
-		
-		std::process::exit(0);
-    }).unwrap_or_else(|_| print_msg("Unable to set the Ctrl+C handler", MsgType::Warning));
 
-	print_msg(format!("Server started, listening on {}", listener.local_addr().unwrap()), MsgType::Info);
+		if panics::internal_errors() > 0 {
+			warn!("{} internal errors occurred while handling requests.", panics::internal_errors());
+		}
 
-	for stream_res in listener.incoming() {
+		std::process::exit(0);
+    }).unwrap_or_else(|_| warn!("Unable to set the Ctrl+C handler"));
 
-		if let Ok(stream) = stream_res {
-			
-			// pool instance for the worker threads
-			let pool = pool.lock().unwrap();
-			
-			pool.execute(move|| {
-				handle_stream(stream);
-			});
+	info!("Server started, listening on {} ({})", listener.local_addr().unwrap(), {
+		if tls_config.is_some() { "HTTPS" } else { "HTTP" }
+	});
+
+	loop {
+		// The address changed in config.toml
+		if let Some(new_listener) = reload::take_new_listener() {
+			listener = new_listener;
 		}
+
+		let stream = match listener.accept() {
+			Ok((s, _)) => s,
+			Err(_) => continue
+		};
+
+		// pool instance for the worker threads
+		let pool = pool.lock().unwrap();
+		let tls_config = tls_config.clone();
+
+		pool.execute(move|| {
+			accept_stream(stream, tls_config);
+		});
 	}
+}
 
-	// Shutdown the threadpool
-	pool.lock().unwrap().join();
+// Reopen the log files and reload config.toml when SIGHUP is received, so that the log files can be
+// moved by external tools (e.g. logrotate) and the configuration changed without restarting the server
+#[cfg(unix)]
+fn handle_sighup(pool: Arc<Mutex<ThreadPool>>) {
+	use signal_hook::consts::SIGHUP;
+	use signal_hook::iterator::Signals;
+
+	let mut signals = match Signals::new([SIGHUP]) {
+		Ok(s) => s,
+		Err(e) => {
+			warn!("Cannot handle SIGHUP: {}", e);
+			return;
+		}
+	};
+
+	std::thread::spawn(move || {
+		for _ in signals.forever() {
+			printing::reopen_log();
+			access_log::reopen();
+			info!("SIGHUP received, log files reopened");
+
+			reload::reload(&pool);
+		}
+	});
 }
 
-fn handle_stream(mut stream: TcpStream) {
+// Wrap the accepted stream in a TLS session if needed, then handle it
+fn accept_stream(stream: TcpStream, tls_config: Option<Arc<ServerConfig>>) {
+	let peer_addr = match stream.peer_addr() {
+		Ok(a) => a,
+		Err(_) => {
+			error!("Cannot get the client address, dropping the connection.");
+			return;
+		}
+	};
 
-	let mut buffer: [u8; 2048] = [0; 2048];
+	// The read timeout changes while the connection is used, the clone refers to the same socket
+	let socket = match stream.try_clone() {
+		Ok(s) => s,
+		Err(e) => {
+			error!("Cannot handle the connection with {}: {}", peer_addr, e);
+			return;
+		}
+	};
 
-	stream.read(&mut buffer).unwrap();
+	// Clients that stop reading the response must not keep the thread busy
+	set_timeout(TcpStream::set_write_timeout, &socket, CONFIG.get().server.write_timeout);
 
-	let current_time = Local::now().format("%H:%M:%S (UTC%:z)");
-	
-	// Performance metrics
-	let now = Instant::now();
+	if let Some(config) = tls_config {
+		let mut tls_stream = StreamOwned::new(ServerSession::new(&config), stream);
 
-	print_msg(format!("[{}] New request.", current_time), MsgType::Info);
-	print_msg(format!("Client address: {}", stream.peer_addr().unwrap().to_string().green()), MsgType::Info);
+		handle_stream(&mut tls_stream, &socket, peer_addr);
 
-	// Elaborate the request
-	let packet_content = &String::from_utf8(buffer.to_vec()).unwrap();
-	let parsed_request = http::parse_request(packet_content);
+		// Tell the client that the TLS session is over
+		tls_stream.sess.send_close_notify();
+		let _ = tls_stream.flush();
+	} else {
+		let mut stream = stream;
 
-	if let Ok(request) = parsed_request {
-		let response = requests_handler::response_builder(request);
+		handle_stream(&mut stream, &socket, peer_addr);
+	}
+}
 
-		http::send_response(stream, response);
+// Set the read or write timeout (in seconds) of a socket
+fn set_timeout(set: fn(&TcpStream, Option<Duration>) -> std::io::Result<()>, socket: &TcpStream, seconds: u64) {
+	if set(socket, Some(Duration::from_secs(seconds))).is_err() {
+		warn!("Cannot set the connection timeout.");
 	}
+}
 
-	let elapsed = now.elapsed();
+fn is_timeout(e: &std::io::Error) -> bool {
+	e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+// Errors returned when the client ends the connection, e.g. rustls turns the close_notify alert of
+// the client into ConnectionAborted
+fn is_closed_by_client(e: &std::io::Error) -> bool {
+	e.kind() == ErrorKind::ConnectionAborted || e.kind() == ErrorKind::UnexpectedEof
+}
 
-	print_msg(format!("Completed in {}ms ({})", elapsed.as_millis(), {
-		let elapsed_secs = elapsed.as_secs();
+// socket is the TCP stream under stream, used to change the read timeout
+fn handle_stream<S: Read + ResponseWriter>(stream: &mut S, socket: &TcpStream, peer_addr: SocketAddr) {
+
+	let mut reader = BufReader::new(stream);
+	let mut served_requests: usize = 0;
+
+	// Serve the requests in the order they arrive, pipelined requests wait in the reader buffer
+	loop {
+		// Between two requests the connection is idle, it gets closed after keep_alive_timeout
+		if served_requests > 0 {
+			set_timeout(TcpStream::set_read_timeout, socket, CONFIG.get().server.keep_alive_timeout);
+
+			match reader.fill_buf() {
+				Ok([]) => break,
+				Ok(_) => (),
+				Err(e) if is_timeout(&e) => {
+					info!("Connection with {} closed after {} seconds of inactivity.", peer_addr, CONFIG.get().server.keep_alive_timeout);
+					break;
+				},
+				Err(e) if is_closed_by_client(&e) => {
+					debug!("Connection closed by {}: {}", peer_addr, e);
+					break;
+				},
+				Err(e) => {
+					error!("Cannot read the request from {}: {}", peer_addr, e);
+					break;
+				}
+			}
+		}
+
+		// The whole request must arrive without pauses longer than request_timeout
+		set_timeout(TcpStream::set_read_timeout, socket, CONFIG.get().server.request_timeout);
+
+		// Read the request, the TLS handshake (if any) takes place while reading the first one
+		let parsed_request = http::read_request(&mut reader, CONFIG.get().server.max_body_size);
+
+		// Messages about the request are tagged with its id and the client address
+		let _context: printing::ContextGuard;
+
+		let received = Local::now();
+		let current_time = received.format("%H:%M:%S (UTC%:z)");
+
+		// Filled in once the response is sent, the duration is added at the end
+		let mut log_entry: Option<access_log::Entry> = None;
 		
-		if elapsed_secs > 0 {
-			format!("{} seconds", elapsed_secs)
-		} else {
-			let elapsed_micros = elapsed.as_micros();
-			format!("{}μs", elapsed_micros)
+		// Performance metrics
+		let now = Instant::now();
+
+		// Elaborate the request
+		let keep_alive = match parsed_request {
+			Ok(request) => {
+				_context = printing::enter_request(peer_addr);
+				info!("[{}] New request.", current_time);
+
+				served_requests += 1;
+
+				// Close the connection if the client asks so or if it has already been used enough
+				let mut keep_alive = http::wants_keep_alive(&request)
+					&& CONFIG.get().server.keep_alive_timeout > 0
+					&& served_requests < CONFIG.get().server.max_requests_per_connection;
+				let version = request.version();
+				let request_line = format!("{} {} {:?}", request.method(), request.uri(), version);
+				let referrer = request.headers().get(REFERER).map(|v| v.as_bytes().to_vec());
+				let user_agent = request.headers().get(USER_AGENT).map(|v| v.as_bytes().to_vec());
+
+				// A panic while building the response must not leave the client waiting
+				let mut response = match panics::catch_request(&request_line, || requests_handler::response_builder(request)) {
+					Ok(r) => r,
+					Err(_) => {
+						keep_alive = false;
+						requests_handler::error_response(500)
+					}
+				};
+
+				// Bodies of unknown length are sent in chunks, HTTP/1.0 clients don't support
+				// them so the end of the body is marked by closing the connection
+				if response.body().len().is_none() {
+					if version == Version::HTTP_10 {
+						keep_alive = false;
+					} else {
+						response.headers_mut().insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+					}
+				}
+
+				if !keep_alive {
+					response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+				} else if version == Version::HTTP_10 {
+					// HTTP/1.0 connections are persistent only if both parties agree
+					response.headers_mut().insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+				}
+
+				let status = response.status().as_u16();
+				let sent = http::send_response(reader.get_mut(), response);
+
+				log_entry = Some(access_log::Entry {
+					client: peer_addr.ip(),
+					time: received,
+					request_line: Some(request_line),
+					status,
+					bytes: sent.unwrap_or(0),
+					referrer,
+					user_agent,
+					duration: Duration::ZERO
+				});
+
+				sent.is_ok() && keep_alive
+			},
+			Err(RequestError::Closed) => break,
+			Err(RequestError::Io(e)) => {
+				if is_timeout(&e) {
+					info!("Connection with {} closed, no data received for {} seconds.", peer_addr, CONFIG.get().server.request_timeout);
+				} else if is_closed_by_client(&e) && reader.buffer().is_empty() {
+					// Nothing of a new request has been received, the connection is just over
+					debug!("Connection closed by {}: {}", peer_addr, e);
+				} else {
+					error!("Cannot read the request from {}: {}", peer_addr, e);
+				}
+
+				break;
+			},
+			Err(e) => {
+				_context = printing::enter_request(peer_addr);
+				info!("[{}] New request.", current_time);
+				warn!("Invalid request from {}: {}", peer_addr, e);
+
+				// The rest of the stream cannot be trusted, the connection gets closed
+				if let Some(status_code) = e.status() {
+					let sent = http::send_response(reader.get_mut(), requests_handler::error_response(status_code));
+
+					log_entry = Some(access_log::Entry {
+						client: peer_addr.ip(),
+						time: received,
+						request_line: None,
+						status: status_code,
+						bytes: sent.unwrap_or(0),
+						referrer: None,
+						user_agent: None,
+						duration: Duration::ZERO
+					});
+				}
+
+				false
+			}
+		};
+
+		let elapsed = now.elapsed();
+
+		if let Some(mut entry) = log_entry {
+			entry.duration = elapsed;
+			access_log::write(&entry);
+		}
+
+		info!("Completed in {}ms ({})", elapsed.as_millis(), {
+			let elapsed_secs = elapsed.as_secs();
+			
+			if elapsed_secs > 0 {
+				format!("{} seconds", elapsed_secs)
+			} else {
+				let elapsed_micros = elapsed.as_micros();
+				format!("{}μs", elapsed_micros)
+			}
+		});
+
+		if !keep_alive {
+			break;
 		}
-	}), MsgType::Info);
-}
\ No newline at end of file
+	}
+}
@@ -0,0 +1,74 @@
+// Panics while handling a request: they are caught, logged with the request and a backtrace, and
+// counted as internal errors so that the client gets a 500 response.
+
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+// Number of requests that caused a panic since the server started
+static INTERNAL_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+// Details of a panic, collected by the hook before the stack is unwound
+struct PanicDetails {
+	message: String,
+	location: String,
+	backtrace: Backtrace,
+}
+
+thread_local! {
+	// Whether the thread is running catch_request
+	static HANDLING_REQUEST: Cell<bool> = const { Cell::new(false) };
+	static LAST_PANIC: RefCell<Option<PanicDetails>> = const { RefCell::new(None) };
+}
+
+// Replace the panic hook. Panics caught by catch_request are logged by it, the others are printed by
+// the default hook.
+pub fn set_hook() {
+	let default_hook = panic::take_hook();
+
+	panic::set_hook(Box::new(move |info| {
+		if !HANDLING_REQUEST.with(|h| h.get()) {
+			default_hook(info);
+			return;
+		}
+
+		let payload = info.payload();
+		let message = payload.downcast_ref::<&str>()
+			.map(|s| s.to_string())
+			.or_else(|| payload.downcast_ref::<String>().cloned())
+			.unwrap_or_else(|| "unknown panic payload".into());
+
+		let location = info.location()
+			.map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+			.unwrap_or_else(|| "unknown location".into());
+
+		let details = PanicDetails { message, location, backtrace: Backtrace::force_capture() };
+		LAST_PANIC.with(|p| *p.borrow_mut() = Some(details));
+	}));
+}
+
+// Run the handler of a request, catching any panic.
+// request_line is only used to log the panic.
+pub fn catch_request<F: FnOnce() -> R, R>(request_line: &str, handler: F) -> Result<R, ()> {
+	HANDLING_REQUEST.with(|h| h.set(true));
+	let result = panic::catch_unwind(AssertUnwindSafe(handler));
+	HANDLING_REQUEST.with(|h| h.set(false));
+
+	result.map_err(|_| {
+		let errors = INTERNAL_ERRORS.fetch_add(1, Ordering::Relaxed) + 1;
+
+		let details = match LAST_PANIC.with(|p| p.borrow_mut().take()) {
+			Some(d) => format!("{} at {}\nBacktrace:\n{}", d.message, d.location, d.backtrace),
+			None => "no details available".into(),
+		};
+
+		error!("Panic while handling \"{}\" (internal errors: {}): {}", request_line, errors, details);
+	})
+}
+
+// Number of internal errors since the server started
+pub fn internal_errors() -> usize {
+	INTERNAL_ERRORS.load(Ordering::Relaxed)
+}
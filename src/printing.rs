@@ -1,54 +1,188 @@
-// This file contains functions used for printing.
-// Use format!() on the text arguments to use these functions as if they were println!().
+// Logger of the webserver, it receives the messages of every module (and of the dependencies) through
+// the `log` macros: error!(), warn!(), info!(), debug!() and trace!().
+// Messages are printed with a marker for their level or written to the log file, tagged with the
+// request being handled by the thread, if any.
 
+use chrono::Local;
 use colored::Colorize;
-use lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
 
-use crate::config::CONFIG;
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-lazy_static!{
-	pub static ref ERROR_MARKER: String = "[E]".red().to_string();
-	pub static ref WARNING_MARKER: String = "[W]".yellow().to_string();
-	pub static ref INFO_MARKER: String = "[I]".clear().to_string();
+use crate::config;
+use crate::log_file::LogFile;
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+// Id of the next request, ids are unique until the server is restarted
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+	// Request being handled by the thread
+	static CONTEXT: Cell<Option<RequestContext>> = const { Cell::new(None) };
 }
 
-pub enum MsgType {
-	Error,
-	Warning,
-	Info
+#[derive(Clone, Copy)]
+struct RequestContext {
+	id: u64,
+	peer: SocketAddr,
 }
 
-pub fn print_markers() {
-	print_msg("<- Error", MsgType::Error);
-	print_msg("<- Warning", MsgType::Warning);
-	print_msg("<- Info", MsgType::Info);
+// The messages of the thread are tagged with the request until this is dropped
+pub struct ContextGuard;
+
+impl Drop for ContextGuard {
+	fn drop(&mut self) {
+		CONTEXT.with(|c| c.set(None));
+	}
 }
 
-// Print a message
-pub fn print_msg<S: Into<String>>(text: S, msg_type: MsgType) {
-	match msg_type {
-		MsgType::Error => {
-			if CONFIG.printing.verbosity >= 1 {
-				eprintln!("{}", format!("{} {}", *ERROR_MARKER, text.into()));
-			}
-		},
-		MsgType::Warning => {
-			if CONFIG.printing.verbosity >= 2 {
-				println!("{}", format!("{} {}", *WARNING_MARKER, text.into()));
+struct Logger {
+	// Maximum level of the modules without a filter
+	level: LevelFilter,
+	// Module paths and their maximum level, longest paths first so that the most specific one is used
+	filters: Vec<(String, LevelFilter)>,
+	// Messages are written here instead of stdout/stderr if a log file is set in config.toml
+	file: Option<Mutex<LogFile>>,
+}
+
+impl Logger {
+	// Maximum level of the messages coming from a module (the target of a record)
+	fn max_level(&self, target: &str) -> LevelFilter {
+		self.filters
+			.iter()
+			.find(|(module, _)| {
+				target.strip_prefix(module.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+			})
+			.map_or(self.level, |(_, level)| *level)
+	}
+}
+
+impl Log for Logger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		metadata.level() <= self.max_level(metadata.target())
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+
+		let text = match CONTEXT.with(|c| c.get()) {
+			Some(context) => format!("[#{} {}] {}", context.id, context.peer, record.args()),
+			None => record.args().to_string(),
+		};
+
+		let level_marker = marker(record.level());
+
+		if let Some(file) = &self.file {
+			let line = format!("{} {} {}", Local::now().format("%Y-%m-%d %H:%M:%S"), level_marker, text);
+
+			// Not logged, it would end up here again
+			if let Err(e) = file.lock().unwrap_or_else(|e| e.into_inner()).write_line(&line) {
+				eprintln!("{} Cannot write the log file: {}", marker(Level::Error), e);
 			}
-		},
-		MsgType::Info => {
-			if CONFIG.printing.verbosity >= 3 {
-				println!("{}", format!("{} {}", *INFO_MARKER, text.into()));
+		} else if record.level() == Level::Error {
+			eprintln!("{} {}", level_marker, text);
+		} else {
+			println!("{} {}", level_marker, text);
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+// Marker printed before the messages of a level
+fn marker(level: Level) -> String {
+	match level {
+		Level::Error => "[E]".red().to_string(),
+		Level::Warn => "[W]".yellow().to_string(),
+		Level::Info => "[I]".clear().to_string(),
+		Level::Debug => "[D]".dimmed().to_string(),
+		Level::Trace => "[T]".dimmed().to_string(),
+	}
+}
+
+// Maximum level of the messages for a verbosity level set through the command line
+fn verbosity_level(verbosity: u8) -> LevelFilter {
+	match verbosity {
+		0 => LevelFilter::Off,
+		1 => LevelFilter::Error,
+		2 => LevelFilter::Warn,
+		3 => LevelFilter::Info,
+		4 => LevelFilter::Debug,
+		_ => LevelFilter::Trace,
+	}
+}
+
+// Set up the logger. Messages logged before this function is called are lost.
+pub fn init(printing: &config::Printing, log: &config::Log) {
+	let file = log.path.as_ref().and_then(|path| {
+		match LogFile::open(path, log) {
+			Ok(f) => Some(Mutex::new(f)),
+			Err(e) => {
+				eprintln!("{} Cannot open the log file {}: {}, messages are printed instead.", marker(Level::Error), path, e);
+				None
 			}
 		}
+	});
+
+	// Log files don't need colors
+	if file.is_some() {
+		colored::control::set_override(false);
+	}
+
+	let mut filters: Vec<(String, LevelFilter)> = log.filters.clone().into_iter().collect();
+	filters.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+
+	let level = verbosity_level(printing.verbosity);
+
+	// Messages above this level are discarded by the macros, before reaching the logger
+	let max_level = filters.iter().map(|(_, l)| *l).fold(level, |max, l| max.max(l));
+
+	let logger = LOGGER.get_or_init(|| Logger { level, filters, file });
+
+	if log::set_logger(logger).is_ok() {
+		log::set_max_level(max_level);
+	}
+}
+
+// Reopen the log file after it has been moved (e.g. by logrotate)
+pub fn reopen_log() {
+	if let Some(file) = LOGGER.get().and_then(|l| l.file.as_ref()) {
+		if let Err(e) = file.lock().unwrap_or_else(|e| e.into_inner()).reopen() {
+			eprintln!("{} Cannot reopen the log file: {}", marker(Level::Error), e);
+		}
 	}
 }
 
+// Tag the messages of the thread with a new request id and the client address, until the returned
+// guard is dropped
+pub fn enter_request(peer: SocketAddr) -> ContextGuard {
+	let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+	CONTEXT.with(|c| c.set(Some(RequestContext { id, peer })));
+
+	ContextGuard
+}
+
+pub fn print_markers() {
+	error!("<- Error");
+	warn!("<- Warning");
+	info!("<- Info");
+	debug!("<- Debug");
+	trace!("<- Trace");
+}
+
 // Prints '\n'
 pub fn print_separator() {
-	// No need to print separators without output
-	if CONFIG.printing.verbosity != 0 {
+	// No need to print separators without output or in the log file
+	let printed = LOGGER.get().is_some_and(|l| l.file.is_none());
+
+	if printed && log::max_level() != LevelFilter::Off {
 		println!();
 	}
 }
@@ -0,0 +1,129 @@
+// Reload of config.toml while the server is running, when SIGHUP is received or when the file changes.
+// Requests received after the reload use the new configuration. The thread pool is resized and a new
+// listener is bound if the address changes, while the settings that are read on startup only (log
+// files and TLS certificate) need a restart.
+
+use threadpool::ThreadPool;
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::cache;
+use crate::config::{Config, CONFIG};
+
+// How often config.toml is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+lazy_static! {
+	// Listener bound to the new address, the main thread takes it after the next accepted connection
+	static ref NEW_LISTENER: Mutex<Option<TcpListener>> = Mutex::new(None);
+
+	// SIGHUP and file changes may trigger a reload at the same time
+	static ref RELOADING: Mutex<()> = Mutex::new(());
+}
+
+// Reload config.toml and apply the changes. If the file cannot be used, the current configuration
+// is kept.
+pub fn reload(pool: &Mutex<ThreadPool>) {
+	let _reloading = RELOADING.lock().unwrap_or_else(|e| e.into_inner());
+	let config_path = CONFIG.get().config_path.clone();
+
+	let (old_config, new_config) = match CONFIG.reload() {
+		Ok(c) => c,
+		Err(e) => {
+			error!("Cannot reload {}, keeping the current configuration: {}", config_path, e);
+			return;
+		}
+	};
+
+	// Pages rendered with the old title or from the old WWW directory must not be sent anymore
+	cache::clear();
+
+	if new_config.server.threads != old_config.server.threads {
+		pool.lock().unwrap().set_num_threads(new_config.server.threads);
+		info!("Thread pool resized, total threads: {}", new_config.server.threads);
+	}
+
+	if new_config.server.address != old_config.server.address {
+		rebind(&old_config.server.address, &new_config.server.address);
+	}
+
+	warn_restart_needed(&old_config, &new_config);
+
+	info!("{} reloaded", config_path);
+}
+
+// Bind the new address and wake up the main thread, which is waiting for a connection on the old one
+fn rebind(old_address: &str, new_address: &str) {
+	let listener = match TcpListener::bind(new_address) {
+		Ok(l) => l,
+		Err(e) => {
+			error!("Cannot bind {}, still listening on {}: {}", new_address, old_address, e);
+			return;
+		}
+	};
+
+	*NEW_LISTENER.lock().unwrap_or_else(|e| e.into_inner()) = Some(listener);
+
+	// A connection to 0.0.0.0 or :: is not possible everywhere, the loopback address is used instead
+	let wake_address = old_address.to_socket_addrs().ok().and_then(|mut a| a.next()).map(|mut a| {
+		match a.ip() {
+			IpAddr::V4(ip) if ip.is_unspecified() => a.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+			IpAddr::V6(ip) if ip.is_unspecified() => a.set_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+			_ => (),
+		}
+
+		a
+	});
+
+	if let Some(a) = wake_address {
+		let _ = TcpStream::connect_timeout(&a, Duration::from_secs(1));
+	}
+
+	info!("Listening on {}", new_address);
+}
+
+// Listener bound to a new address by a reload, if any
+pub fn take_new_listener() -> Option<TcpListener> {
+	NEW_LISTENER.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+fn warn_restart_needed(old_config: &Config, new_config: &Config) {
+	let changed: [(&str, bool); 3] = [
+		("[log]", new_config.log != old_config.log),
+		("[access_log]", new_config.access_log != old_config.access_log),
+		("cert_path and key_path", new_config.server.cert_path != old_config.server.cert_path
+			|| new_config.server.key_path != old_config.server.key_path),
+	];
+
+	for (settings, is_changed) in changed {
+		if is_changed {
+			warn!("Changes to {} take effect after a restart", settings);
+		}
+	}
+}
+
+// Reload config.toml when it changes, its modification time is checked periodically
+pub fn watch_config_file(pool: Arc<Mutex<ThreadPool>>) {
+	let config_path = CONFIG.get().config_path.clone();
+	let modified = move || -> Option<SystemTime> {
+		fs::metadata(&config_path).and_then(|m| m.modified()).ok()
+	};
+
+	let mut last_modified = modified();
+
+	thread::spawn(move || loop {
+		thread::sleep(POLL_INTERVAL);
+
+		let current_modified = modified();
+
+		if current_modified != last_modified {
+			last_modified = current_modified;
+			info!("{} changed, reloading it...", CONFIG.get().config_path);
+			reload(&pool);
+		}
+	});
+}
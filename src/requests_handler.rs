@@ -1,75 +1,504 @@
-use http::{Request, Response, Method};
+use http::{Request, Response, Method, StatusCode, Version};
+use http::header::{ACCEPT_ENCODING, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE};
 
-use crate::router::get_routes;
-use crate::printing::{print_msg, MsgType};
-use crate::config::{self, CONFIG};
-use crate::io_ops;
-use crate::html::md_to_html;
+use std::io::{self, prelude::*, Cursor};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-fn error_404() -> (Vec<u8>, String, u16) {
+use crate::body::Body;
+use crate::compression::{self, Encoding};
+use crate::http::{format_http_date, parse_http_date, parse_range, percent_decode, trim_ows};
+use crate::router::{get_routes, RouteResponse};
+use crate::config::CONFIG;
+use crate::io_ops::{self, FileError};
+use crate::html::{default_error_page, md_to_html};
 
-	if let Ok(mut content) = io_ops::get_file_content(&CONFIG.server.err404_path) {
+// Choose a route based on the method and the URI.
+// Returns None if no route matches.
+fn choose_route(req_method: &Method, req_uri: &str) -> Option<Result<RouteResponse, FileError>> {
 
-		// Translate markdown page
-		if CONFIG.server.err404_path.ends_with(".md") {
-			content = md_to_html(&CONFIG.server.err404_path)
-				.unwrap()
-				.as_bytes()
-				.to_vec();
+	for route in get_routes() {
+		if route.is_complete_match(req_method.clone(), req_uri) {
+			return Some(route.handle(req_uri));
+		}
+	}
+
+	debug!("Route \"{} {}\" not found.", req_method, req_uri);
+	None
+}
+
+// Methods that can be used on a path, based on the registered routes. The patterns of the routes
+// match any path, so a route counts only if there is something to send (the files are not read).
+// The "*" path (OPTIONS * HTTP/1.1) refers to the whole server.
+fn allowed_methods(req_uri: &str) -> Vec<Method> {
+	let mut methods: Vec<Method> = vec![];
+
+	for route in get_routes() {
+		if methods.contains(route.method()) {
+			continue;
 		}
 
-		let mg = mime_guess::from_path(&CONFIG.server.err404_path);
-		let mime_type: String;
+		let exists = req_uri == "*" || (route.is_path_match(req_uri)
+			&& !matches!(route.exists(req_uri), Err(FileError::NotFound)));
 
-		if let Some(t) = mg.first() {
-			mime_type = t.to_string();
-		} else {
-			mime_type = "text/plain".into();
+		if exists {
+			methods.push(route.method().clone());
 		}
+	}
 
-		(content, mime_type, 404)
-	} else {
-		print_msg(format!("404 error page (\"{}\") does not exist, using default page content.", CONFIG.server.err404_path), MsgType::Warning);
+	// HEAD is available wherever GET is
+	if methods.contains(&Method::GET) {
+		methods.push(Method::HEAD);
+	}
 
-		(config::DEFAULT_404_PAGE_CONTENT.into(), "text/plain".into(), 404)
+	if !methods.is_empty() {
+		methods.push(Method::OPTIONS);
 	}
+
+	methods
 }
 
-// Choose a route based on the method and the URI
-fn choose_route<'a>(req_method: &Method, req_uri: &str) -> Option<(Vec<u8>, String, u16)> {
+fn allow_header_value(methods: &[Method]) -> String {
+	methods.iter()
+		.map(|m| m.as_str())
+		.collect::<Vec<&str>>()
+		.join(", ")
+}
 
-	for route in get_routes() {
-		if route.is_complete_match(req_method.clone(), req_uri) {
-			return route.handle(req_uri);
+// Returns: body content, content MIME type (html, plain text, etc...), status code and the
+// value of the Allow header for 405 responses
+fn handle_request(req_method: &Method, req_uri: &str) -> (RouteResponse, Option<String>) {
+
+	info!("Request: {} {}", req_method, req_uri);
+
+	// HEAD requests are handled by the GET routes, the body gets removed later
+	let route_method = if req_method == Method::HEAD { Method::GET } else { req_method.clone() };
+
+	match choose_route(&route_method, req_uri) {
+		Some(Ok(response)) => return (response, None),
+		Some(Err(FileError::NotFound)) | None => (),
+		Some(Err(e)) => {
+			warn!("Cannot send {}: {}.", req_uri, e);
+			return (error_page(e.status()), None);
+		},
+	}
+
+	// The path exists but not with this method
+	let allowed = allowed_methods(req_uri);
+	if !allowed.is_empty() && !allowed.contains(req_method) {
+		return (error_page(405), Some(allow_header_value(&allowed)));
+	}
+
+	(error_page(404), None)
+}
+
+// Answer to OPTIONS requests with the methods available for the path
+fn options_response(req_uri: &str) -> Response<Body> {
+
+	info!("Request: OPTIONS {}", req_uri);
+
+	let allowed = allowed_methods(req_uri);
+
+	if allowed.is_empty() {
+		let not_found = error_page(404);
+
+		return Response::builder()
+			.status(not_found.status)
+			.header("Content-Type", not_found.mime_type)
+			.header("Content-Length", not_found.body.len().unwrap_or(0))
+			.body(not_found.body)
+			.unwrap();
+	}
+
+	Response::builder()
+		.status(200)
+		.header("Allow", allow_header_value(&allowed))
+		.header("Content-Length", 0)
+		.body(Body::empty())
+		.unwrap()
+}
+
+// Whether the client already has the current version of the content (RFC 9110 section 13.2.2).
+// If-None-Match takes precedence over If-Modified-Since.
+fn is_not_modified(req: &Request<Vec<u8>>, response: &RouteResponse) -> bool {
+	let headers = req.headers();
+
+	if headers.contains_key(IF_NONE_MATCH) {
+		let etag = match &response.etag {
+			Some(e) => e.trim_start_matches("W/"),
+			None => return false
+		};
+
+		// Weak comparison: the W/ prefix is ignored. Entity tags are opaque, they may contain any
+		// byte (obs-text), so they are compared as bytes.
+		return headers.get_all(IF_NONE_MATCH)
+			.iter()
+			.flat_map(|v| v.as_bytes().split(|c| *c == b','))
+			.map(trim_ows)
+			.any(|t| t == b"*" || t.strip_prefix(b"W/").unwrap_or(t) == etag.as_bytes());
+	}
+
+	if let (Some(since), Some(modified)) = (headers.get(IF_MODIFIED_SINCE), response.last_modified) {
+		if let Some(since) = since.to_str().ok().and_then(parse_http_date) {
+			// HTTP dates have a precision of one second
+			let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+			let since_secs = since.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+			return modified_secs <= since_secs;
 		}
 	}
 
-	print_msg(format!("Route \"{} {}\" not found.", req_method, req_uri), MsgType::Error);
-	None
+	false
+}
+
+// Whether the Range header can be used: If-Range, if present, must match the current
+// validators through a strong comparison (RFC 9110 section 13.1.5)
+fn is_range_applicable(req: &Request<Vec<u8>>, response: &RouteResponse) -> bool {
+	let if_range = match req.headers().get(IF_RANGE) {
+		Some(v) => v.as_bytes(),
+		None => return true
+	};
+
+	if if_range.starts_with(b"\"") {
+		return response.etag.as_ref().map(|e| e.as_bytes()) == Some(if_range);
+	}
+
+	// Weak tags never match
+	if if_range.starts_with(b"W/") {
+		return false;
+	}
+
+	// Dates are ASCII, anything else is not a valid date
+	let date = std::str::from_utf8(if_range).ok().and_then(parse_http_date);
+
+	match (date, response.last_modified) {
+		(Some(date), Some(modified)) => {
+			let date_secs = date.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+			let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+			date_secs == modified_secs
+		},
+		_ => false
+	}
+}
+
+// Build a multipart/byteranges body (RFC 9110 section 14.6) made of the requested ranges.
+// Returns the body and its content type, None if the body cannot be split.
+fn multipart_body(body: &Body, ranges: &[(u64, u64)], content_type: &str) -> Option<(Body, String)> {
+	let full_len = body.len()?;
+
+	// The boundary must not appear in the body, a random-looking one is enough in practice
+	let boundary = format!("{:016x}", SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_nanos() as u64)
+		.unwrap_or(0) ^ full_len);
+
+	// The parts are read one after another while sending
+	let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
+	let mut len: u64 = 0;
+
+	for (first, last) in ranges {
+		let part_head = format!(
+			"\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+			boundary, content_type, first, last, full_len
+		).into_bytes();
+		let section = body.section(*first, *last)?;
+
+		len += part_head.len() as u64 + section.len()?;
+		reader = Box::new(reader.chain(Cursor::new(part_head)).chain(section.into_reader()));
+	}
+
+	let end = format!("\r\n--{}--\r\n", boundary).into_bytes();
+	len += end.len() as u64;
+	reader = Box::new(reader.chain(Cursor::new(end)));
+
+	Some((Body::Stream { reader, len: Some(len) }, format!("multipart/byteranges; boundary={}", boundary)))
 }
 
-// Returns: body content, content MIME type (html, plain text, etc...), status code
-fn handle_request<'a>(req_method: &Method, req_uri: &str) -> (Vec<u8>, String, u16) {
+// Choose the content coding of a response, the returned bool tells whether the body must still be
+// compressed. Precompressed versions of static files (file.br, file.zst, file.gz) replace the body
+// immediately, other contents are compressed on the fly if they are large enough.
+fn negotiate_encoding(req: &Request<Vec<u8>>, response: &mut RouteResponse) -> Option<(Encoding, bool)> {
+	if !CONFIG.get().compression.enabled || response.status != 200 {
+		return None;
+	}
+
+	let accepted = req.headers()
+		.get(ACCEPT_ENCODING)
+		.map(|a| compression::negotiate(&String::from_utf8_lossy(a.as_bytes())))
+		.unwrap_or_default();
+
+	if let Some(path) = &response.file_path {
+		for encoding in &accepted {
+			if let Ok((file, len)) = io_ops::open_file(format!("{}.{}", path, encoding.extension())) {
+				debug!("Sending the precompressed file {}.{}", path, encoding.extension());
 
-	print_msg(format!("Request: {} {}", req_method.to_string(), req_uri), MsgType::Info);
+				response.body = Body::File { file, offset: 0, len };
 
-	let response: Option<(Vec<u8>, String, u16)> = choose_route(req_method, req_uri);
+				// Each representation needs its own strong tag
+				response.etag = response.etag
+					.take()
+					.map(|e| format!("{}-{}\"", e.trim_end_matches('"'), encoding.extension()));
 
-	if let None = response {
-		return error_404();
+				return Some((*encoding, false));
+			}
+		}
 	}
 
-	return response.unwrap();
+	let is_too_small = response.body.len().is_some_and(|l| l < CONFIG.get().compression.min_size);
+
+	if accepted.is_empty() || is_too_small || !compression::is_compressible(&response.mime_type) {
+		return None;
+	}
+
+	// The length of the compressed content is unknown, ranges cannot be served
+	response.accept_ranges = false;
+	response.etag = response.etag
+		.take()
+		.map(|e| if e.starts_with("W/") { e } else { format!("W/{}", e) });
+
+	Some((accepted[0], true))
 }
 
-pub fn response_builder(req: Request<String>) -> Response<Vec<u8>> {
+pub fn response_builder(req: Request<Vec<u8>>) -> Response<Body> {
+
+	// Routes work on the decoded path, e.g. "/caf%C3%A9" becomes "/café"
+	let path = match percent_decode(req.uri().path()) {
+		Some(p) => p,
+		None => {
+			warn!("Invalid percent-encoding in path {}", req.uri().path());
+
+			let page = error_page(400);
+			return Response::builder()
+				.status(400)
+				.header("Content-Type", page.mime_type)
+				.header("Content-Length", page.body.len().unwrap_or(0))
+				.body(page.body)
+				.unwrap();
+		}
+	};
+
+	if req.method() == Method::OPTIONS {
+		return options_response(&path);
+	}
 	
-	let (resp_body, content_type, status_code) = handle_request(req.method(), req.uri().path());
+	let (mut response, allow) = handle_request(req.method(), &path);
 
-	Response::builder()
+	let mut builder = Response::builder();
+
+	// The query is kept when redirecting
+	if let Some(location) = &response.location {
+		match req.uri().query() {
+			Some(q) => builder = builder.header("Location", format!("{}?{}", location, q)),
+			None => builder = builder.header("Location", location),
+		}
+	}
+
+	let encoding = negotiate_encoding(&req, &mut response);
+
+	// Caches must not send a compressed response to a client that doesn't support it
+	if CONFIG.get().compression.enabled && response.status == 200
+		&& (encoding.is_some() || compression::is_compressible(&response.mime_type)) {
+		builder = builder.header("Vary", "Accept-Encoding");
+	}
+
+	if let Some(e) = &response.etag {
+		builder = builder.header("ETag", e);
+	}
+
+	if let Some(m) = response.last_modified {
+		builder = builder.header("Last-Modified", format_http_date(m));
+	}
+
+	// The client cache is still valid
+	if response.status == 200 && is_not_modified(&req, &response) {
+		return builder
+			.status(304)
+			.body(Body::empty())
+			.unwrap();
+	}
+
+	if response.accept_ranges {
+		builder = builder.header("Accept-Ranges", "bytes");
+	}
+
+	let range_header = req.headers()
+		.get(RANGE)
+		.and_then(|r| r.to_str().ok())
+		.filter(|_| response.status == 200 && response.accept_ranges && is_range_applicable(&req, &response));
+
+	let mut status_code = response.status;
+	let mut content_type = response.mime_type;
+	let mut body = response.body;
+
+	// Partial content
+	if let (Some(range_header), Some(full_len)) = (range_header, body.len()) {
+		match parse_range(range_header, full_len).as_deref() {
+			// Invalid header, ignored
+			None => (),
+			Some([]) => {
+				let page = error_page(416);
+
+				return builder
+					.status(416)
+					.header("Content-Range", format!("bytes */{}", full_len))
+					.header("Content-Type", page.mime_type)
+					.header("Content-Length", page.body.len().unwrap_or(0))
+					.body(page.body)
+					.unwrap();
+			},
+			Some([(first, last)]) => {
+				if let Some(section) = body.section(*first, *last) {
+					builder = builder.header("Content-Range", format!("bytes {}-{}/{}", first, last, full_len));
+					body = section;
+					status_code = 206;
+				}
+			},
+			Some(ranges) => {
+				if let Some((multipart, multipart_type)) = multipart_body(&body, ranges, &content_type) {
+					body = multipart;
+					content_type = multipart_type;
+					status_code = 206;
+				}
+			}
+		}
+	}
+
+	if let Some((e, compress)) = encoding {
+		if compress {
+			body = match compression::compress(body, e) {
+				Ok(b) => b,
+				Err(err) => {
+					error!("Cannot compress the response: {}", err);
+
+					let page = error_page(500);
+					return Response::builder()
+						.status(500)
+						.header("Content-Type", page.mime_type)
+						.header("Content-Length", page.body.len().unwrap_or(0))
+						.body(page.body)
+						.unwrap();
+				}
+			};
+		}
+
+		builder = builder.header("Content-Encoding", e.name());
+	}
+
+	builder = builder
 		.status(status_code)
-		.header("Content-Type", content_type)
-		.header("Content-Length", resp_body.len())
-		.body(resp_body)
+		.header("Content-Type", content_type);
+
+	// Without Content-Length the body is sent in chunks
+	if let Some(len) = body.len() {
+		builder = builder.header("Content-Length", len);
+	}
+
+	if let Some(a) = allow {
+		builder = builder.header("Allow", a);
+	}
+
+	// Same headers as GET, without the body. GET sends bodies of unknown length (e.g. compressed on the
+	// fly) in chunks, except to HTTP/1.0 clients.
+	if req.method() == Method::HEAD {
+		if body.len().is_none() && req.version() != Version::HTTP_10 {
+			builder = builder.header("Transfer-Encoding", "chunked");
+		}
+
+		return builder.body(Body::empty()).unwrap();
+	}
+
+	builder.body(body).unwrap()
+}
+
+// Page sent with error status codes: the page configured in config.toml if any, otherwise a
+// built-in page
+fn error_page(status_code: u16) -> RouteResponse {
+	let status = StatusCode::from_u16(status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+	if let Some(path) = CONFIG.get().errors.pages.get(&status.as_u16()) {
+		match custom_error_page(path) {
+			Ok((content, mime_type)) => return RouteResponse::new(Body::Bytes(content), mime_type, status.as_u16()),
+			Err(e) => warn!("Error page {} for status {} cannot be used ({}), using the default page.", path, status.as_u16(), e),
+		}
+	}
+
+	let html = default_error_page(status.as_u16(), status.canonical_reason().unwrap_or("Unknown error"));
+
+	RouteResponse::new(Body::Bytes(html.into_bytes()), "text/html".into(), status.as_u16())
+}
+
+// Content and MIME type of an error page file, Markdown pages are rendered into HTML
+fn custom_error_page(path: &str) -> Result<(Vec<u8>, String), FileError> {
+	if path.ends_with(".md") {
+		return Ok((md_to_html(path)?.into_bytes(), "text/html".into()));
+	}
+
+	let content = io_ops::get_file_content(path)?;
+	let mime_type = mime_guess::from_path(path)
+		.first()
+		.map(|t| t.to_string())
+		.unwrap_or_else(|| "text/plain".into());
+
+	Ok((content, mime_type))
+}
+
+// Response sent when the request cannot be handled (malformed, too large, etc...)
+pub fn error_response(status_code: u16) -> Response<Body> {
+	let page = error_page(status_code);
+
+	Response::builder()
+		.status(page.status)
+		.header("Content-Type", page.mime_type)
+		.header("Content-Length", page.body.len().unwrap_or(0))
+		.header("Connection", "close")
+		.body(page.body)
 		.unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::time::Duration;
+
+	fn request(if_range: &str) -> Request<Vec<u8>> {
+		Request::builder()
+			.header(RANGE, "bytes=0-9")
+			.header(IF_RANGE, if_range)
+			.body(vec![])
+			.unwrap()
+	}
+
+	fn file_response() -> RouteResponse {
+		let mut response = RouteResponse::new(Body::empty(), "text/plain".into(), 200);
+		response.etag = Some("\"5f5e100.0-64\"".into());
+		response.last_modified = Some(UNIX_EPOCH + Duration::from_secs(100_000_000));
+
+		response
+	}
+
+	#[test]
+	fn range_without_if_range() {
+		let req = Request::builder().header(RANGE, "bytes=0-9").body(vec![]).unwrap();
+
+		assert!(is_range_applicable(&req, &file_response()));
+	}
+
+	#[test]
+	fn if_range_with_etag() {
+		assert!(is_range_applicable(&request("\"5f5e100.0-64\""), &file_response()));
+
+		// The content changed, the whole content is sent with 200
+		assert!(!is_range_applicable(&request("\"5f5e100.0-65\""), &file_response()));
+
+		// Weak tags never match
+		assert!(!is_range_applicable(&request("W/\"5f5e100.0-64\""), &file_response()));
+	}
+
+	#[test]
+	fn if_range_with_date() {
+		assert!(is_range_applicable(&request("Sat, 03 Mar 1973 09:46:40 GMT"), &file_response()));
+		assert!(!is_range_applicable(&request("Sat, 03 Mar 1973 09:46:41 GMT"), &file_response()));
+		assert!(!is_range_applicable(&request("not a date"), &file_response()));
+	}
+}
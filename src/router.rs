@@ -1,11 +1,75 @@
 use libhttp::Method;
-use mime_guess;
 use regex::Regex;
 
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache;
+use crate::config::CONFIG;
+use crate::http::percent_encode;
+use crate::listing;
 use crate::html::md_to_html;
-use crate::io_ops::get_file_content;
+use crate::body::Body;
+use crate::io_ops::{get_file_content, get_file_metadata, open_file, FileError};
 use crate::css::{get_default_grass_options, sass_to_css};
 
+// Returned by the route handlers
+pub struct RouteResponse {
+	pub body: Body,
+	// Content MIME type (html, plain text, etc...)
+	pub mime_type: String,
+	pub status: u16,
+	// Modification time of the file the body comes from
+	pub last_modified: Option<SystemTime>,
+	// Entity tag, including quotes and the W/ prefix if weak
+	pub etag: Option<String>,
+	// Whether parts of the body can be requested through the Range header
+	pub accept_ranges: bool,
+	// Path (relative to WWW) of the static file sent as body, if any
+	pub file_path: Option<String>,
+	// Target of a redirection (Location header), already percent-encoded
+	pub location: Option<String>,
+}
+
+impl RouteResponse {
+	pub fn new(body: Body, mime_type: String, status: u16) -> RouteResponse {
+		RouteResponse {
+			body,
+			mime_type,
+			status,
+			last_modified: None,
+			etag: None,
+			accept_ranges: false,
+			file_path: None,
+			location: None
+		}
+	}
+
+	// Permanent redirection to another path of the website
+	fn redirect(path: &str) -> RouteResponse {
+		let mut response = RouteResponse::new(Body::empty(), "text/plain".into(), 301);
+		response.location = Some(percent_encode(path));
+
+		response
+	}
+
+	// Set Last-Modified and ETag from the metadata of a file in WWW.
+	// Weak tags are used for content generated from the file (HTML from Markdown, CSS from SCSS).
+	fn with_validators(mut self, path: &str, weak: bool) -> RouteResponse {
+		if let Ok(metadata) = get_file_metadata(path) {
+			if let Ok(modified) = metadata.modified() {
+				let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+				let tag = format!("\"{:x}.{:x}-{:x}\"", since_epoch.as_secs(), since_epoch.subsec_nanos(), metadata.len());
+
+				self.last_modified = Some(modified);
+				self.etag = Some(if weak { format!("W/{}", tag) } else { tag });
+			}
+		}
+
+		self
+	}
+}
+
 // Webserver routes
 pub fn get_routes() -> Vec<Route> {
 	vec![
@@ -19,107 +83,285 @@ pub fn get_routes() -> Vec<Route> {
 		//		 }
 		//	 }
 		// ),
-		Route::new( // Markdown pages, valid for index ("/") as well
-			Method::GET, r"^(/[0-9A-z-_]*)+",
+		Route::new( // Directories, valid for index ("/") as well
+			Method::GET, r"(?s)^/(.*/)?$",
 			|req_uri| {
 
-				// Remove the first character ('/') to get the markdown page name
-				let md_page_path = {
-					if req_uri == "/" {
-						"index.md".into() // Index route ("/")
-					} else {
-						format!("{}.md", req_uri.strip_prefix('/').unwrap())
-					}
-				};
+				// Remove the first character ('/') to get the directory path
+				let dir_path = req_uri.strip_prefix('/').unwrap();
 
-				let converted_md = md_to_html(&md_page_path);
+				match get_file_metadata(dir_path) {
+					Ok(m) if m.is_dir() => (),
+					// The page has been requested with a trailing slash ("/about/" instead of "/about")
+					Ok(_) | Err(FileError::NotFound) => {
+						let page_uri = req_uri.trim_end_matches('/');
 
-				if let Err(_) = converted_md {
-					return None;
+						if page_uri.is_empty() {
+							return Err(FileError::NotFound);
+						}
+
+						return resolve_page(&page_uri[1..]).map(|_| Target::Redirect(page_uri.into()));
+					},
+					Err(e) => return Err(e),
 				}
 
-				Some((converted_md.unwrap().as_bytes().to_vec(), "text/html".into(), 200))
+				let md_index = format!("{}index.md", dir_path);
+				let html_index = format!("{}index.html", dir_path);
+
+				// The index page of the directory, if any, otherwise the directory listing
+				if is_regular_file(&md_index) {
+					Ok(Target::Markdown(md_index))
+				} else if is_regular_file(&html_index) {
+					Ok(Target::File(html_index))
+				} else if listing::is_listing_enabled(dir_path) {
+					Ok(Target::Listing)
+				} else {
+					Err(FileError::NotFound)
+				}
 			}
 		),
-		Route::new( // Other files (CSS, HTML, etc...) except Markdown
-			Method::GET, r"^(/.+\..+)",
+		Route::new( // Files (CSS, HTML, etc...) and pages (Markdown or HTML files without extension)
+			Method::GET, r"(?s)^/.*[^/]$",
 			|req_uri| {
-				
-				// Remove the first character ('/') and return the file
-				let file_name = req_uri.strip_prefix('/').unwrap();
-
-				if let Some(f_content) = get_checked_file_content(&file_name.into()) {
-					// Guess the MIME type
-					let mg = mime_guess::from_path(file_name);
-					let mut mime_type: String;
-
-					// The first guess is probably the most accurate
-					if let Some(t) = mg.first() {
-						mime_type = t.to_string();
-					} else {
-						mime_type = "*/*".into();
-					}
-
-					// Compile SCSS to CSS if needed
-					if file_name.ends_with(".scss") {
-						let final_content = sass_to_css(String::from_utf8(f_content).unwrap(), get_default_grass_options());
-						mime_type = "text/css".into();
-	
-						return Some((final_content.as_bytes().to_vec(), mime_type, 200))
-					}
-
-					Some((f_content, mime_type, 200))
-				} else {
-					None
+
+				// Remove the first character ('/') to get the file path
+				let path = req_uri.strip_prefix('/').unwrap();
+
+				match resolve_page(path) {
+					Ok(file) if file.ends_with(".md") && file != path => Ok(Target::Markdown(file)),
+					Ok(file) => Ok(Target::File(file)),
+					// The directory has been requested without the trailing slash
+					Err(FileError::NotFound) if is_directory(path) => Ok(Target::Redirect(format!("{}/", req_uri))),
+					Err(e) => Err(e),
 				}
 			}
 		)
 	]
 }
 
+// What a path refers to, found through the metadata of the files only
+pub enum Target {
+	// Markdown page rendered into HTML (path relative to WWW)
+	Markdown(String),
+	// File sent as it is, SCSS is compiled to CSS (path relative to WWW)
+	File(String),
+	// Listing of the requested directory
+	Listing,
+	// Permanent redirection to another path of the website
+	Redirect(String),
+}
 
 pub struct Route {
 	method: Method,
 	uri: Regex,
-	handler: fn(&str) -> Option<(Vec<u8>, String, u16)>
+	// Finds what the path refers to, without reading the files
+	find_target: fn(&str) -> Result<Target, FileError>
 }
 
 impl Route {
-	pub fn new(method: Method, uri_str: &str, handler: fn(&str) -> Option<(Vec<u8>, String, u16)>) -> Route {
+	pub fn new(method: Method, uri_str: &str, find_target: fn(&str) -> Result<Target, FileError>) -> Route {
 		Route {
-			method: method,
+			method,
 			uri: Regex::new(uri_str).unwrap(),
-			handler: handler
+			find_target
 		}
 	}
 
 
-	pub fn is_complete_match(&self, method: Method, path: &str) -> bool {
-		if self.method == method {
-			if let Some(r_match) = self.uri.find(path) {
-				if r_match.start() == 0 && r_match.end() == path.len() {
-					return true;
-				}
+	pub fn method(&self) -> &Method {
+		&self.method
+	}
+
+	// Whether the route URI matches the whole path, regardless of the method
+	pub fn is_path_match(&self, path: &str) -> bool {
+		if let Some(r_match) = self.uri.find(path) {
+			if r_match.start() == 0 && r_match.end() == path.len() {
+				return true;
 			}
 		}
 
 		false
 	}
 
-	pub fn handle(&self, req_uri: &str) -> Option<(Vec<u8>, String, u16)> {
-		let h = self.handler;
-		h(req_uri)
+	pub fn is_complete_match(&self, method: Method, path: &str) -> bool {
+		self.method == method && self.is_path_match(path)
+	}
+
+	// Whether the path refers to something that this route can send
+	pub fn exists(&self, req_uri: &str) -> Result<(), FileError> {
+		(self.find_target)(req_uri).map(|_| ())
+	}
+
+	pub fn handle(&self, req_uri: &str) -> Result<RouteResponse, FileError> {
+		match (self.find_target)(req_uri)? {
+			Target::Markdown(file) => markdown_page(&file),
+			Target::File(file) => static_file(&file),
+			Target::Listing => listing::render_listing(req_uri).map(|html| {
+				RouteResponse::new(Body::Bytes(html.into_bytes()), "text/html".into(), 200)
+			}),
+			Target::Redirect(path) => Ok(RouteResponse::redirect(&path)),
+		}
 	}
 }
 
-// Gets the file and returns Some(...)/None based on the Result returned by get_file_content()
-// The function(s) are wrappers that basically tell whether a file exists or not
-fn get_checked_file_content(path: &String) -> Option<Vec<u8>> {
-	let file_content = get_file_content(&path);
+// HTML page rendered from a Markdown file (path relative to WWW)
+fn markdown_page(md_page_path: &str) -> Result<RouteResponse, FileError> {
+
+	let converted_md = md_to_html(md_page_path)?;
+
+	let mut response = RouteResponse::new(Body::Bytes(converted_md.into_bytes()), "text/html".into(), 200)
+		.with_validators(md_page_path, true);
 
-	if let Err(_) = file_content {
-		return None;
+	// The page contains the title set in config.toml, the validators must change when it's reloaded
+	// with another title. The tag must be the same after a restart, so the hash doesn't use a random
+	// seed.
+	let config = CONFIG.get();
+
+	response.etag = response.etag.map(|e| format!("{}-{:x}\"", e.trim_end_matches('"'), fnv1a(config.server.title.as_bytes())));
+	response.last_modified = response.last_modified.map(|m| m.max(config.loaded));
+
+	Ok(response)
+}
+
+// 64-bit FNV-1a hash
+fn fnv1a(bytes: &[u8]) -> u64 {
+	bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3))
+}
+
+// File sent as it is, except for SCSS which is compiled to CSS (path relative to WWW)
+fn static_file(file_name: &str) -> Result<RouteResponse, FileError> {
+
+	// Compile SCSS to CSS if needed, the result is cached
+	if file_name.ends_with(".scss") {
+		let css = cache::get_or_render(file_name, || {
+			let f_content = get_file_content(file_name)?;
+			let sass = String::from_utf8(f_content)
+				.map_err(|e| FileError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+			sass_to_css(sass, get_default_grass_options())
+				.map_err(|_| FileError::Io(io::Error::new(io::ErrorKind::InvalidData, "cannot compile SCSS")))
+		})?;
+
+		return Ok(
+			RouteResponse::new(Body::Bytes(css.into_bytes()), "text/css".into(), 200)
+				.with_validators(file_name, true)
+		);
+	}
+
+	// The file is sent while it's read
+	let (file, len) = open_file(file_name)?;
+
+	// Guess the MIME type
+	let mg = mime_guess::from_path(file_name);
+	let mime_type: String;
+
+	// The first guess is probably the most accurate
+	if let Some(t) = mg.first() {
+		mime_type = t.to_string();
+	} else {
+		mime_type = "*/*".into();
+	}
+
+	let body = Body::File { file, offset: 0, len };
+	let mut response = RouteResponse::new(body, mime_type, 200).with_validators(file_name, false);
+	response.accept_ranges = true;
+	response.file_path = Some(file_name.into());
+
+	Ok(response)
+}
+
+// Find the file to send for a path (relative to WWW) without trailing slash, trying in order the
+// file itself, the Markdown page and the HTML page
+fn resolve_page(path: &str) -> Result<String, FileError> {
+	for candidate in [path.to_owned(), format!("{}.md", path), format!("{}.html", path)] {
+		match get_file_metadata(&candidate) {
+			Ok(m) if m.is_file() => return Ok(candidate),
+			Ok(_) | Err(FileError::NotFound) => (),
+			Err(e) => return Err(e),
+		}
+	}
+
+	Err(FileError::NotFound)
+}
+
+fn is_directory(path: &str) -> bool {
+	get_file_metadata(path).is_ok_and(|m| m.is_dir())
+}
+
+fn is_regular_file(path: &str) -> bool {
+	get_file_metadata(path).is_ok_and(|m| m.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::fs;
+
+	use crate::config::test_www_path;
+	use crate::http::percent_decode;
+
+	// Files of the tests, in their own directory of WWW
+	fn create_files(files: &[&str]) {
+		for file in files {
+			let path = test_www_path().join("router").join(file);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, file).unwrap();
+		}
+	}
+
+	#[test]
+	fn page_lookup_order() {
+		create_files(&["both", "both.md", "both.html", "page.md", "page.html", "only_html.html"]);
+
+		// The file itself, then the Markdown page, then the HTML page
+		assert_eq!(resolve_page("router/both").unwrap(), "router/both");
+		assert_eq!(resolve_page("router/page").unwrap(), "router/page.md");
+		assert_eq!(resolve_page("router/only_html").unwrap(), "router/only_html.html");
+		assert!(matches!(resolve_page("router/missing"), Err(FileError::NotFound)));
+	}
+
+	#[test]
+	fn targets() {
+		create_files(&["targets/index.md", "targets/page.md", "targets/style.css"]);
+		let routes = get_routes();
+		let find = |uri: &str| routes.iter()
+			.find(|r| r.is_path_match(uri))
+			.map(|r| (r.find_target)(uri))
+			.unwrap();
+
+		assert!(matches!(find("/router/targets/"), Ok(Target::Markdown(f)) if f == "router/targets/index.md"));
+		assert!(matches!(find("/router/targets/page"), Ok(Target::Markdown(f)) if f == "router/targets/page.md"));
+		assert!(matches!(find("/router/targets/page.md"), Ok(Target::File(f)) if f == "router/targets/page.md"));
+		assert!(matches!(find("/router/targets/style.css"), Ok(Target::File(_))));
+		assert!(matches!(find("/router/targets"), Ok(Target::Redirect(p)) if p == "/router/targets/"));
+		assert!(matches!(find("/router/targets/page/"), Ok(Target::Redirect(p)) if p == "/router/targets/page"));
+		assert!(matches!(find("/router/targets/missing"), Err(FileError::NotFound)));
+		assert!(routes.iter().all(|r| r.exists("/router/targets/missing").is_err()));
 	}
 
-	Some(file_content.unwrap())
-}
\ No newline at end of file
+	#[test]
+	fn title_hash() {
+		// Reference values of FNV-1a, the tags must not change between builds
+		assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+		assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+		assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+	}
+
+	#[test]
+	fn parent_directory_outside_www() {
+		// A file next to WWW that must never be reached
+		let outside = test_www_path().with_extension("outside.md");
+		fs::write(&outside, "secret").unwrap();
+		let name = outside.file_name().unwrap().to_str().unwrap().trim_end_matches(".md").to_owned();
+
+		for uri in [format!("/%2e%2e/{}", name), format!("/router/%2E%2E/%2e%2e/{}.md", name), format!("/..%2f{}", name)] {
+			let path = percent_decode(&uri).unwrap();
+			let result = resolve_page(path.strip_prefix('/').unwrap());
+
+			assert!(matches!(result, Err(FileError::Forbidden(_))), "{} has not been refused", uri);
+		}
+
+		fs::remove_file(outside).unwrap();
+	}
+}
@@ -0,0 +1,67 @@
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+// Open a PEM file, the error message already contains the file role (certificate chain, private key)
+fn open_pem_file(path: &str, role: &str) -> Result<BufReader<File>, String> {
+	match File::open(path) {
+		Ok(f) => Ok(BufReader::new(f)),
+		Err(e) => Err(format!("Cannot open the TLS {} file \"{}\": {}", role, path, e))
+	}
+}
+
+// Read the certificate chain (leaf certificate first) from a PEM file
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+	let mut reader = open_pem_file(path, "certificate chain")?;
+
+	let chain = certs(&mut reader)
+		.map_err(|_| format!("Cannot parse the TLS certificate chain file \"{}\": invalid PEM content.", path))?;
+
+	if chain.is_empty() {
+		return Err(format!("The TLS certificate chain file \"{}\" does not contain any certificate.", path));
+	}
+
+	Ok(chain)
+}
+
+// Read the private key from a PEM file, both PKCS#8 and RSA (PKCS#1) keys are accepted
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+	let mut reader = open_pem_file(path, "private key")?;
+	let mut keys = pkcs8_private_keys(&mut reader)
+		.map_err(|_| format!("Cannot parse the TLS private key file \"{}\": invalid PEM content.", path))?;
+
+	// No PKCS#8 key, try again looking for RSA keys
+	if keys.is_empty() {
+		let mut reader = open_pem_file(path, "private key")?;
+		keys = rsa_private_keys(&mut reader)
+			.map_err(|_| format!("Cannot parse the TLS private key file \"{}\": invalid PEM content.", path))?;
+	}
+
+	if keys.is_empty() {
+		return Err(format!("The TLS private key file \"{}\" does not contain any PKCS#8 or RSA private key.", path));
+	}
+
+	Ok(keys.remove(0))
+}
+
+// Build the rustls configuration shared by all the TLS sessions
+// cert_path and key_path are not relative to WWW
+pub fn load_server_config(cert_path: Option<&String>, key_path: Option<&String>) -> Result<Arc<ServerConfig>, String> {
+	let cert_path = cert_path
+		.ok_or("TLS is enabled but \"cert_path\" is not set in the [server] section of config.toml (use --no-tls to serve plain HTTP).")?;
+	let key_path = key_path
+		.ok_or("TLS is enabled but \"key_path\" is not set in the [server] section of config.toml (use --no-tls to serve plain HTTP).")?;
+
+	let chain = load_certs(cert_path)?;
+	let key = load_private_key(key_path)?;
+
+	let mut config = ServerConfig::new(NoClientAuth::new());
+
+	config.set_single_cert(chain, key)
+		.map_err(|e| format!("Invalid TLS certificate/private key pair (\"{}\", \"{}\"): {}", cert_path, key_path, e))?;
+
+	Ok(Arc::new(config))
+}